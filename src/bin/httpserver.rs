@@ -1,15 +1,15 @@
-use httpfromtcp::{SERVER_PORT, Serve, shutdown_signal};
-use tokio::net::TcpListener;
+use httpfromtcp::{SERVER_PORT, Server, shutdown_signal};
+use std::net::Ipv4Addr;
 
 #[tokio::main]
 async fn main() -> Result<(), anyhow::Error> {
     tracing_subscriber::fmt::init();
 
-    let listener = TcpListener::bind(("127.0.0.1", SERVER_PORT)).await?;
+    let server = Server::builder()
+        .bind((Ipv4Addr::LOCALHOST, SERVER_PORT))
+        .build();
 
-    let signal = shutdown_signal();
-
-    Serve::serve(listener, signal).await?;
+    server.serve(shutdown_signal()).await?;
 
     Ok(())
 }
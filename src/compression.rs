@@ -0,0 +1,527 @@
+use crate::{Body, HTTPParsingError, Request, Response, parse_quality_list};
+use bytes::Bytes;
+use flate2::{
+    read::{MultiGzDecoder, ZlibDecoder},
+    write::{GzEncoder, ZlibEncoder},
+};
+use std::io::{self, Read, Write};
+
+/// Below this many bytes, compressing isn't worth the CPU or the framing.
+const DEFAULT_MIN_SIZE: u64 = 1024;
+
+/// Brotli's quality ranges from 0 to 11; past 5 it gets slow for little gain
+/// on the fly.
+const BROTLI_QUALITY: u32 = 5;
+const BROTLI_WINDOW: u32 = 22;
+
+/// In-memory bodies past this size are compressed on the blocking pool
+/// rather than on an async worker.
+const BLOCKING_MIN_SIZE: usize = 16 * 1024;
+
+/// A content coding responses can be compressed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentCoding {
+    Brotli,
+    Gzip,
+    Deflate,
+}
+
+impl ContentCoding {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Brotli => "br",
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+        }
+    }
+
+    /// The coding named `name` in a `Content-Encoding` or `Accept-Encoding`.
+    pub fn from_name(name: &str) -> Option<Self> {
+        [Self::Brotli, Self::Gzip, Self::Deflate]
+            .into_iter()
+            .find(|c| c.matches(name))
+    }
+
+    fn matches(self, name: &str) -> bool {
+        name.eq_ignore_ascii_case(self.as_str())
+            || (self == Self::Gzip && name.eq_ignore_ascii_case("x-gzip"))
+    }
+}
+
+/// Which responses get compressed, and how. Off unless set with
+/// [`Builder::compression`](crate::Builder::compression).
+///
+/// A response is compressed when its type is text-like, it's at least
+/// [`Compression::min_size`] bytes, it isn't a range or already encoded, and
+/// the client's `Accept-Encoding` allows one of the codings. In-memory bodies
+/// keep a `Content-Length`; files are compressed as they're sent, chunked.
+#[derive(Debug, Clone)]
+pub struct Compression {
+    min_size: u64,
+    codings: Vec<ContentCoding>,
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            min_size: DEFAULT_MIN_SIZE,
+            codings: vec![
+                ContentCoding::Brotli,
+                ContentCoding::Gzip,
+                ContentCoding::Deflate,
+            ],
+        }
+    }
+}
+
+impl Compression {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Bodies smaller than this go out as they are. Defaults to 1 KiB.
+    pub fn min_size(mut self, bytes: u64) -> Self {
+        self.min_size = bytes;
+        self
+    }
+
+    /// The codings to offer, most preferred first. The client's q-values
+    /// decide; this order only breaks ties.
+    pub fn codings(mut self, codings: impl IntoIterator<Item = ContentCoding>) -> Self {
+        self.codings = codings.into_iter().collect();
+        self
+    }
+
+    /// Picks the coding `accept_encoding` ranks highest, or `None` if it
+    /// accepts none of ours.
+    pub fn negotiate(&self, accept_encoding: &str) -> Option<ContentCoding> {
+        let mut wildcard = None;
+        let mut ranked = Vec::new();
+
+        for item in parse_quality_list(accept_encoding) {
+            match item.value {
+                "*" => wildcard = Some(item.q),
+                name => ranked.push((name, item.q)),
+            }
+        }
+
+        let mut best: Option<(ContentCoding, f32)> = None;
+        for &coding in &self.codings {
+            let q = ranked
+                .iter()
+                .find(|(name, _)| coding.matches(name))
+                .map(|(_, q)| *q)
+                .or(wildcard);
+
+            if let Some(q) = q
+                && q > 0.0
+                && best.is_none_or(|(_, b)| q > b)
+            {
+                best = Some((coding, q));
+            }
+        }
+
+        best.map(|(coding, _)| coding)
+    }
+
+    /// Compresses `res` for `req` if both allow it.
+    pub async fn compress(&self, req: &Request, mut res: Response) -> Response {
+        let headers = &res.head.headers;
+        let eligible = res.head.status.permits_body()
+            && res.head.status.as_u16() != 206
+            && !headers.0.contains_key("content-range")
+            && !headers.0.contains_key("content-encoding")
+            // The body is already framed; leave it alone.
+            && !headers.0.contains_key("transfer-encoding")
+            && !headers
+                .get("cache-control")
+                .is_some_and(|c| c.to_ascii_lowercase().contains("no-transform"))
+            && headers.get("content-type").is_some_and(|t| compressible(t))
+            && res.body.len() >= self.min_size;
+        if !eligible {
+            return res;
+        }
+
+        // Whether or not we compress, the answer depended on the header.
+        res.head.headers.add_vary("accept-encoding");
+
+        let Some(coding) = req
+            .head
+            .headers
+            .get("accept-encoding")
+            .and_then(|a| self.negotiate(a))
+        else {
+            return res;
+        };
+
+        let body = std::mem::take(&mut res.body);
+        let headers = &mut res.head.headers;
+        match body.as_bytes() {
+            Some(bytes) => match compress_off_worker(coding, bytes).await {
+                Ok(compressed) => {
+                    headers
+                        .replace("content-length", compressed.len().to_string())
+                        .ok();
+                    res.body = compressed.into();
+                }
+                Err(err) => {
+                    tracing::warn!("failed to compress response: {err}");
+                    res.body = body;
+                    return res;
+                }
+            },
+            None => {
+                headers.delete("content-length").ok();
+                headers
+                    .replace("transfer-encoding", "chunked".to_string())
+                    .ok();
+                res.body = Body::compressed(body, coding);
+            }
+        }
+
+        headers
+            .replace("content-encoding", coding.as_str().to_string())
+            .ok();
+        // A range would count bytes of the uncompressed body.
+        headers.delete("accept-ranges").ok();
+
+        // The compressed bytes differ, so a strong ETag no longer fits them.
+        if let Some(etag) = headers.get("etag")
+            && etag.starts_with('"')
+        {
+            let weak = format!("W/{etag}");
+            headers.replace("etag", weak).ok();
+        }
+
+        res
+    }
+}
+
+/// Whether compressing `content_type` is likely to pay off. Media, archives
+/// and fonts are already compressed.
+fn compressible(content_type: &str) -> bool {
+    let mime = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+
+    mime.starts_with("text/")
+        || mime.ends_with("+json")
+        || mime.ends_with("+xml")
+        || matches!(
+            mime.as_str(),
+            "application/json"
+                | "application/javascript"
+                | "application/xml"
+                | "application/wasm"
+                | "application/x-ndjson"
+        )
+}
+
+/// Compresses `bytes`, on the blocking pool unless there are only a few.
+async fn compress_off_worker(coding: ContentCoding, bytes: &Bytes) -> io::Result<Bytes> {
+    if bytes.len() < BLOCKING_MIN_SIZE {
+        return compress_bytes(coding, bytes);
+    }
+
+    let bytes = bytes.clone();
+    tokio::task::spawn_blocking(move || compress_bytes(coding, &bytes)).await?
+}
+
+fn compress_bytes(coding: ContentCoding, bytes: &[u8]) -> io::Result<Bytes> {
+    let mut compressor = Compressor::new(coding);
+    let mut out = compressor.write(bytes)?;
+    out.extend(compressor.finish()?);
+
+    Ok(out.into())
+}
+
+/// Decompresses a request body, refusing to produce more than `limit`
+/// bytes so a small upload can't expand into gigabytes.
+pub(crate) fn decompress(
+    coding: ContentCoding,
+    data: &[u8],
+    limit: Option<u64>,
+) -> Result<Vec<u8>, HTTPParsingError> {
+    let decoder: Box<dyn Read + '_> = match coding {
+        ContentCoding::Brotli => Box::new(brotli::Decompressor::new(data, 4096)),
+        // Concatenated gzip members are one body.
+        ContentCoding::Gzip => Box::new(MultiGzDecoder::new(data)),
+        ContentCoding::Deflate => Box::new(ZlibDecoder::new(data)),
+    };
+
+    let mut out = Vec::new();
+    decoder
+        // One byte over tells a body at the limit from one past it.
+        .take(limit.map_or(u64::MAX, |l| l.saturating_add(1)))
+        .read_to_end(&mut out)
+        .map_err(|_| HTTPParsingError::BadBody)?;
+
+    if limit.is_some_and(|l| out.len() as u64 > l) {
+        return Err(HTTPParsingError::BodyTooLarge);
+    }
+
+    Ok(out)
+}
+
+/// Incremental compression: feed input, take whatever output is ready.
+pub(crate) enum Compressor {
+    Brotli(Box<brotli::CompressorWriter<Vec<u8>>>),
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl Compressor {
+    pub(crate) fn new(coding: ContentCoding) -> Self {
+        let level = flate2::Compression::default();
+
+        match coding {
+            ContentCoding::Brotli => Self::Brotli(Box::new(brotli::CompressorWriter::new(
+                Vec::new(),
+                4096,
+                BROTLI_QUALITY,
+                BROTLI_WINDOW,
+            ))),
+            ContentCoding::Gzip => Self::Gzip(GzEncoder::new(Vec::new(), level)),
+            // HTTP's "deflate" is the zlib format, not raw deflate.
+            ContentCoding::Deflate => Self::Deflate(ZlibEncoder::new(Vec::new(), level)),
+        }
+    }
+
+    /// Compresses `input`, returning the output produced so far.
+    pub(crate) fn write(&mut self, input: &[u8]) -> io::Result<Vec<u8>> {
+        let out = match self {
+            Self::Brotli(w) => {
+                w.write_all(input)?;
+                w.get_mut()
+            }
+            Self::Gzip(w) => {
+                w.write_all(input)?;
+                w.get_mut()
+            }
+            Self::Deflate(w) => {
+                w.write_all(input)?;
+                w.get_mut()
+            }
+        };
+
+        Ok(std::mem::take(out))
+    }
+
+    /// Ends the stream, returning the rest of the output.
+    pub(crate) fn finish(self) -> io::Result<Vec<u8>> {
+        match self {
+            Self::Brotli(w) => Ok(w.into_inner()),
+            Self::Gzip(w) => w.finish(),
+            Self::Deflate(w) => w.finish(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Encode, StatusCode};
+
+    fn request(accept_encoding: &str) -> Request {
+        let mut req = Request::new();
+        req.head
+            .headers
+            .replace("accept-encoding", accept_encoding.to_string())
+            .unwrap();
+        req
+    }
+
+    fn page() -> Response {
+        Response::new(Some("<p>hello</p>".repeat(200)))
+            .content_type("text/html; charset=utf-8")
+            .unwrap()
+    }
+
+    fn decode(coding: &str, bytes: &[u8]) -> String {
+        let coding = ContentCoding::from_name(coding).unwrap();
+        String::from_utf8(decompress(coding, bytes, None).unwrap()).unwrap()
+    }
+
+    /// Undoes chunked framing, ignoring extensions and trailers.
+    fn dechunk(mut bytes: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        loop {
+            let line = bytes.windows(2).position(|w| w == b"\r\n").unwrap();
+            let size =
+                usize::from_str_radix(std::str::from_utf8(&bytes[..line]).unwrap(), 16).unwrap();
+            if size == 0 {
+                return out;
+            }
+            out.extend_from_slice(&bytes[line + 2..line + 2 + size]);
+            bytes = &bytes[line + 2 + size + 2..];
+        }
+    }
+
+    #[test]
+    fn negotiates_by_q_value() {
+        let c = Compression::default();
+
+        assert_eq!(
+            Some(ContentCoding::Brotli),
+            c.negotiate("gzip, deflate, br")
+        );
+        assert_eq!(Some(ContentCoding::Gzip), c.negotiate("br;q=0.5, gzip"));
+        assert_eq!(Some(ContentCoding::Gzip), c.negotiate("x-gzip"));
+        assert_eq!(
+            Some(ContentCoding::Deflate),
+            c.negotiate("*;q=0.1, deflate;Q=0.8, br;q=0")
+        );
+        assert_eq!(Some(ContentCoding::Brotli), c.negotiate("*"));
+        assert_eq!(None, c.negotiate("identity"));
+        assert_eq!(None, c.negotiate("gzip;q=0, *;q=0"));
+        assert_eq!(None, c.negotiate(""));
+    }
+
+    #[tokio::test]
+    async fn revalidation_sees_the_weakened_etag() {
+        let mut req = request("gzip");
+        req.head
+            .headers
+            .replace("if-none-match", r#"W/"p""#.to_string())
+            .unwrap();
+
+        // The order the connection uses: compress, then check preconditions.
+        let res = Compression::default()
+            .compress(&req, page().etag(&crate::ETag::strong("p")))
+            .await;
+        let res = crate::preconditions(&req, res);
+
+        assert_eq!(crate::StatusCode::NOT_MODIFIED, res.head.status);
+        assert_eq!(r#"W/"p""#, res.head.headers.get("etag").unwrap());
+        assert_eq!("accept-encoding", res.head.headers.get("vary").unwrap());
+    }
+
+    #[tokio::test]
+    async fn compresses_in_memory_bodies() {
+        let c = Compression::default();
+
+        for coding in ["br", "gzip", "deflate"] {
+            let res = c
+                .compress(&request(coding), page().etag(&crate::ETag::strong("p")))
+                .await;
+            let body = res.body.as_bytes().unwrap();
+
+            assert_eq!(coding, res.head.headers.get("content-encoding").unwrap());
+            assert_eq!("accept-encoding", res.head.headers.get("vary").unwrap());
+            assert_eq!(r#"W/"p""#, res.head.headers.get("etag").unwrap());
+            assert_eq!(
+                body.len().to_string(),
+                *res.head.headers.get("content-length").unwrap()
+            );
+            assert_eq!("<p>hello</p>".repeat(200), decode(coding, body));
+        }
+    }
+
+    #[tokio::test]
+    async fn compresses_large_bodies_on_the_blocking_pool() {
+        let text = "<p>hello</p>".repeat(5000);
+        assert!(text.len() >= BLOCKING_MIN_SIZE);
+
+        let res = Response::new(Some(text.clone()))
+            .content_type("text/html")
+            .unwrap();
+        let res = Compression::default().compress(&request("br"), res).await;
+
+        assert_eq!("br", res.head.headers.get("content-encoding").unwrap());
+        assert_eq!(text, decode("br", res.body.as_bytes().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn skips_ineligible_responses() {
+        let c = Compression::default();
+        let req = request("gzip");
+
+        let small = Response::new(Some("<p>hi</p>"))
+            .content_type("text/html")
+            .unwrap();
+        assert!(
+            c.compress(&req, small)
+                .await
+                .head
+                .headers
+                .get("content-encoding")
+                .is_none()
+        );
+
+        let video = Response::new(Some(vec![0u8; 4096]))
+            .content_type("video/mp4")
+            .unwrap();
+        let video = c.compress(&req, video).await;
+        assert!(video.head.headers.get("content-encoding").is_none());
+        assert!(video.head.headers.get("vary").is_none());
+
+        let mut partial = page();
+        partial.head.status = StatusCode::PARTIAL_CONTENT;
+        assert!(
+            c.compress(&req, partial)
+                .await
+                .head
+                .headers
+                .get("content-encoding")
+                .is_none()
+        );
+
+        // Still varies: another client might have been sent gzip.
+        let plain = c.compress(&request("identity"), page()).await;
+        assert!(plain.head.headers.get("content-encoding").is_none());
+        assert_eq!("accept-encoding", plain.head.headers.get("vary").unwrap());
+    }
+
+    #[test]
+    fn decompresses_up_to_the_limit() {
+        let text = "a".repeat(10_000);
+        let gzip = compress_bytes(ContentCoding::Gzip, text.as_bytes()).unwrap();
+
+        assert_eq!(
+            text.as_bytes(),
+            decompress(ContentCoding::Gzip, &gzip, Some(10_000)).unwrap()
+        );
+        assert!(matches!(
+            decompress(ContentCoding::Gzip, &gzip, Some(9_999)),
+            Err(HTTPParsingError::BodyTooLarge)
+        ));
+        assert!(matches!(
+            decompress(ContentCoding::Deflate, &gzip, None),
+            Err(HTTPParsingError::BadBody)
+        ));
+    }
+
+    #[tokio::test]
+    async fn streams_file_bodies_chunked() {
+        let path =
+            std::env::temp_dir().join(format!("httpfromtcp-{}-gzip.txt", std::process::id()));
+        let text = "line of text\n".repeat(5000);
+        std::fs::write(&path, &text).unwrap();
+
+        let file = std::fs::File::open(&path).unwrap();
+        let mut res = Response::new(Some(Body::file(file, 0, text.len() as u64)))
+            .content_type("text/plain")
+            .unwrap();
+        res.head
+            .headers
+            .replace("accept-ranges", "bytes".to_string())
+            .unwrap();
+        let res = Compression::default().compress(&request("gzip"), res).await;
+
+        assert_eq!(
+            "chunked",
+            res.head.headers.get("transfer-encoding").unwrap()
+        );
+        assert!(res.head.headers.get("content-length").is_none());
+        assert!(res.head.headers.get("accept-ranges").is_none());
+
+        let mut out = Vec::new();
+        res.body.write(&mut out).await.unwrap();
+        assert_eq!(text, decode("gzip", &dechunk(&out)));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,559 @@
+use crate::{Body, Headers, Request, Response, ServerError, StatusCode};
+use sha2::{Digest, Sha256};
+use std::{
+    fmt,
+    fs::Metadata,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// An entity tag, as sent in `ETag` and matched against `If-Match`,
+/// `If-None-Match` and `If-Range`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ETag {
+    weak: bool,
+    tag: String,
+}
+
+impl ETag {
+    /// A tag promising byte-for-byte identical content.
+    pub fn strong(tag: impl Into<String>) -> Self {
+        Self {
+            weak: false,
+            tag: tag.into(),
+        }
+    }
+
+    /// A tag promising only equivalent content.
+    pub fn weak(tag: impl Into<String>) -> Self {
+        Self {
+            weak: true,
+            tag: tag.into(),
+        }
+    }
+
+    /// A strong tag hashed from the content itself.
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        Self::strong(short_hash(bytes))
+    }
+
+    /// A strong tag for a file, hashed from its size and modification time.
+    /// Cheap, and changes whenever either does.
+    pub fn from_metadata(meta: &Metadata) -> Self {
+        let modified = meta
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+
+        Self::strong(short_hash(
+            format!("{}-{}", meta.len(), modified.as_nanos()).as_bytes(),
+        ))
+    }
+
+    pub fn is_weak(&self) -> bool {
+        self.weak
+    }
+
+    /// Parses `"abc"` or `W/"abc"`.
+    pub fn parse(s: &str) -> Option<Self> {
+        let s = s.trim();
+        let (weak, quoted) = match s.strip_prefix("W/") {
+            Some(rest) => (true, rest),
+            None => (false, s),
+        };
+
+        let tag = quoted.strip_prefix('"')?.strip_suffix('"')?;
+        if tag.contains('"') {
+            return None;
+        }
+
+        Some(Self {
+            weak,
+            tag: tag.to_string(),
+        })
+    }
+
+    /// Both strong and equal: the comparison `If-Match` and `If-Range` use.
+    pub fn strong_eq(&self, other: &ETag) -> bool {
+        !self.weak && !other.weak && self.tag == other.tag
+    }
+
+    /// Equal tags, weak or not: the comparison `If-None-Match` uses.
+    pub fn weak_eq(&self, other: &ETag) -> bool {
+        self.tag == other.tag
+    }
+}
+
+impl fmt::Display for ETag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.weak {
+            f.write_str("W/")?;
+        }
+        write!(f, "\"{}\"", self.tag)
+    }
+}
+
+fn short_hash(bytes: &[u8]) -> String {
+    hex::encode(&Sha256::digest(bytes)[..16])
+}
+
+impl Response {
+    /// Sets the `ETag` header.
+    pub fn etag(mut self, etag: &ETag) -> Self {
+        self.head.headers.replace("etag", etag.to_string()).ok();
+        self
+    }
+
+    /// Sets the `Last-Modified` header, to the second.
+    pub fn last_modified(mut self, time: SystemTime) -> Self {
+        self.head
+            .headers
+            .replace("last-modified", fmt_http_date(time))
+            .ok();
+        self
+    }
+}
+
+/// What a target resource currently looks like, for evaluating conditional
+/// requests against.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Validators {
+    pub etag: Option<ETag>,
+    pub last_modified: Option<SystemTime>,
+}
+
+impl Validators {
+    /// The validators `res` carries in `ETag` and `Last-Modified`.
+    pub fn of(res: &Response) -> Self {
+        let headers = &res.head.headers;
+        Self {
+            etag: headers.get("etag").and_then(|e| ETag::parse(e)),
+            last_modified: headers
+                .get("last-modified")
+                .and_then(|d| parse_http_date(d)),
+        }
+    }
+}
+
+impl Request {
+    /// Evaluates the conditional headers against `current`, the target's
+    /// validators before anything changes, or `None` if it has no current
+    /// representation. The connection calls this before routing `PUT`,
+    /// `POST`, `DELETE` and the like, with the validators a `GET` of the
+    /// target carries.
+    ///
+    /// Fails with [`ServerError::PreconditionFailed`]. A `GET` or `HEAD`
+    /// that would get a `304` passes; [`preconditions`] answers that.
+    pub fn check_preconditions(&self, current: Option<&Validators>) -> Result<(), ServerError> {
+        match evaluate(self, current) {
+            Some(StatusCode::PRECONDITION_FAILED) => Err(ServerError::PreconditionFailed),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Evaluates `req`'s conditional headers against the validators on `res`,
+/// in the order RFC 9110 section 13.2.2 gives, replacing `res` with a
+/// `304 Not Modified` or `412 Precondition Failed` when one fails.
+///
+/// Only a `2xx` answer to `GET` or `HEAD` is checked: the conditions are
+/// about the representation, which an error doesn't have, and any other
+/// method has already acted by now. Those go through
+/// [`Request::check_preconditions`] before they are routed.
+pub fn preconditions(req: &Request, res: Response) -> Response {
+    let safe = req.head.method == "GET" || req.head.method == "HEAD";
+    if !safe || !res.head.status.is_success() {
+        return res;
+    }
+
+    match evaluate(req, Some(&Validators::of(&res))) {
+        Some(status) => failed(status, &res),
+        None => res,
+    }
+}
+
+/// The status `req`'s conditions call for instead of acting on `current`,
+/// if any.
+fn evaluate(req: &Request, current: Option<&Validators>) -> Option<StatusCode> {
+    let headers = &req.head.headers;
+    let modified = current.and_then(|c| c.last_modified);
+    let safe = req.head.method == "GET" || req.head.method == "HEAD";
+
+    if let Some(if_match) = headers.get("if-match") {
+        if !matches_any(if_match, current, ETag::strong_eq) {
+            return Some(StatusCode::PRECONDITION_FAILED);
+        }
+    } else if let Some(since) = headers.get("if-unmodified-since")
+        && let (Some(since), Some(modified)) = (parse_http_date(since), modified)
+        && modified > since
+    {
+        return Some(StatusCode::PRECONDITION_FAILED);
+    }
+
+    if let Some(if_none_match) = headers.get("if-none-match") {
+        if matches_any(if_none_match, current, ETag::weak_eq) {
+            return match safe {
+                true => Some(StatusCode::NOT_MODIFIED),
+                false => Some(StatusCode::PRECONDITION_FAILED),
+            };
+        }
+    } else if safe
+        && let Some(since) = headers.get("if-modified-since")
+        && let (Some(since), Some(modified)) = (parse_http_date(since), modified)
+        && modified <= since
+    {
+        return Some(StatusCode::NOT_MODIFIED);
+    }
+
+    None
+}
+
+/// Whether `list` matches `current`. `*` matches any current
+/// representation, tagged or not; otherwise one of the comma-separated tags
+/// must match its `ETag`.
+fn matches_any(list: &str, current: Option<&Validators>, eq: fn(&ETag, &ETag) -> bool) -> bool {
+    if list.trim() == "*" {
+        return current.is_some();
+    }
+    let Some(etag) = current.and_then(|c| c.etag.as_ref()) else {
+        return false;
+    };
+
+    etag_list(list)
+        .into_iter()
+        .filter_map(ETag::parse)
+        .any(|tag| eq(&tag, etag))
+}
+
+/// Splits a list of entity-tags at the commas between them. A comma inside
+/// the quotes, as in `"a,b"`, is part of the tag.
+fn etag_list(list: &str) -> Vec<&str> {
+    let mut tags = Vec::new();
+    let (mut start, mut quoted) = (0, false);
+
+    for (i, c) in list.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                tags.push(&list[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    tags.push(&list[start..]);
+
+    tags
+}
+
+/// A bodiless `304` or `412` in place of `res`. A `304` keeps the headers a
+/// cache needs to update what it has stored.
+fn failed(status: StatusCode, res: &Response) -> Response {
+    let mut out = Response::new(Some(Body::default()));
+    out.head.status = status;
+
+    if status == StatusCode::NOT_MODIFIED {
+        out.head.headers = Headers::new();
+        for name in [
+            "etag",
+            "last-modified",
+            "cache-control",
+            "content-location",
+            "date",
+            "expires",
+            "vary",
+        ] {
+            if let Some(value) = res.head.headers.get(name) {
+                out.head.headers.replace(name, value.clone()).ok();
+            }
+        }
+    }
+
+    out
+}
+
+const DAYS: [&str; 7] = ["Sun", "Mon", "Tue", "Wed", "Thu", "Fri", "Sat"];
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Formats `time` as an IMF-fixdate: `Sun, 06 Nov 1994 08:49:37 GMT`.
+pub fn fmt_http_date(time: SystemTime) -> String {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let days = (secs / 86400) as i64;
+    let (year, month, day) = civil_from_days(days);
+    let rem = secs % 86400;
+
+    format!(
+        "{}, {:02} {} {} {:02}:{:02}:{:02} GMT",
+        // 1970-01-01 was a Thursday.
+        DAYS[((days + 4) % 7) as usize],
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        rem / 3600,
+        rem / 60 % 60,
+        rem % 60
+    )
+}
+
+/// Parses an HTTP date in any of the three formats RFC 9110 makes
+/// recipients accept: IMF-fixdate, RFC 850 and asctime.
+pub fn parse_http_date(s: &str) -> Option<SystemTime> {
+    let s = s.trim();
+    let parts: Vec<&str> = s.split_ascii_whitespace().collect();
+
+    let (day, month, year, time) = match parts.as_slice() {
+        // Sun, 06 Nov 1994 08:49:37 GMT
+        [_, day, month, year, time, "GMT"] if s.contains(',') => {
+            (day.parse().ok()?, *month, year.parse().ok()?, *time)
+        }
+        // Sunday, 06-Nov-94 08:49:37 GMT
+        [_, date, time, "GMT"] => {
+            let mut date = date.split('-');
+            let day = date.next()?.parse().ok()?;
+            let month = date.next()?;
+            let year: i64 = date.next()?.parse().ok()?;
+            // Two-digit years more than 50 years out are in the past.
+            let year = if year < 70 { 2000 + year } else { 1900 + year };
+            (day, month, year, *time)
+        }
+        // Sun Nov  6 08:49:37 1994
+        [_, month, day, time, year] => (day.parse().ok()?, *month, year.parse().ok()?, *time),
+        _ => return None,
+    };
+
+    let month = MONTHS.iter().position(|m| *m == month)? as u32 + 1;
+    let mut hms = time.split(':').map(|n| n.parse::<u64>().ok());
+    let (h, m, sec) = (hms.next()??, hms.next()??, hms.next()??);
+    if day == 0 || day > 31 || h > 23 || m > 59 || sec > 60 || hms.next().is_some() {
+        return None;
+    }
+
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    let secs = days * 86400 + h * 3600 + m * 60 + sec;
+
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Days since 1970-01-01 to a (year, month, day) date, after Howard
+/// Hinnant's `civil_from_days`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (year, month, day)
+}
+
+/// The inverse of [`civil_from_days`].
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (i64::from(month) + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + i64::from(day) - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATE: &str = "Sun, 06 Nov 1994 08:49:37 GMT";
+
+    fn at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    fn request(method: &str, headers: &[(&str, &str)]) -> Request {
+        let mut req = Request::new();
+        req.head.method = crate::Method::from_bytes(method.as_bytes()).unwrap();
+        for (k, v) in headers {
+            req.head.headers.replace(k, v.to_string()).unwrap();
+        }
+        req
+    }
+
+    fn asset() -> Response {
+        Response::new(Some("body"))
+            .etag(&ETag::strong("v1"))
+            .last_modified(parse_http_date(DATE).unwrap())
+    }
+
+    fn status(req: &Request) -> StatusCode {
+        preconditions(req, asset()).head.status
+    }
+
+    /// What a handler acting on `asset()` would answer after checking first.
+    fn checked(req: &Request) -> StatusCode {
+        match req.check_preconditions(Some(&Validators::of(&asset()))) {
+            Ok(()) => StatusCode::OK,
+            Err(err) => err.status_code(),
+        }
+    }
+
+    #[test]
+    fn http_dates_round_trip() {
+        assert_eq!(Some(at(784111777)), parse_http_date(DATE));
+        assert_eq!(DATE, fmt_http_date(at(784111777)));
+        assert_eq!(
+            Some(at(784111777)),
+            parse_http_date("Sunday, 06-Nov-94 08:49:37 GMT")
+        );
+        assert_eq!(
+            Some(at(784111777)),
+            parse_http_date("Sun Nov  6 08:49:37 1994")
+        );
+        assert_eq!(
+            "Thu, 29 Feb 2024 00:00:00 GMT",
+            fmt_http_date(at(1709164800))
+        );
+        assert_eq!(None, parse_http_date("yesterday"));
+        assert_eq!(None, parse_http_date("Sun, 06 Nov 1994 25:49:37 GMT"));
+    }
+
+    #[test]
+    fn etags() {
+        assert_eq!(Some(ETag::weak("x")), ETag::parse(r#"W/"x""#));
+        assert_eq!(None, ETag::parse("x"));
+        assert!(ETag::strong("x").strong_eq(&ETag::strong("x")));
+        assert!(!ETag::weak("x").strong_eq(&ETag::strong("x")));
+        assert!(ETag::weak("x").weak_eq(&ETag::strong("x")));
+        assert_eq!(ETag::from_bytes(b"a"), ETag::from_bytes(b"a"));
+        assert_ne!(ETag::from_bytes(b"a"), ETag::from_bytes(b"b"));
+    }
+
+    #[test]
+    fn if_none_match() {
+        let req = request("GET", &[("if-none-match", r#""v0", W/"v1""#)]);
+        let res = preconditions(&req, asset());
+        assert_eq!(StatusCode::NOT_MODIFIED, res.head.status);
+        assert_eq!(r#""v1""#, res.head.headers.get("etag").unwrap());
+        assert!(res.body.is_empty());
+
+        assert_eq!(
+            StatusCode::OK,
+            status(&request("GET", &[("if-none-match", r#""v2""#)]))
+        );
+        assert_eq!(
+            StatusCode::PRECONDITION_FAILED,
+            checked(&request("POST", &[("if-none-match", "*")]))
+        );
+    }
+
+    #[test]
+    fn commas_inside_entity_tags() {
+        let tagged = || Response::new(Some("body")).etag(&ETag::strong("a,b"));
+
+        let req = request("GET", &[("if-none-match", r#""x", "a,b""#)]);
+        assert_eq!(
+            StatusCode::NOT_MODIFIED,
+            preconditions(&req, tagged()).head.status
+        );
+
+        let req = request("GET", &[("if-none-match", r#""a", "b""#)]);
+        assert_eq!(StatusCode::OK, preconditions(&req, tagged()).head.status);
+
+        let req = request("PUT", &[("if-match", r#""a,b""#)]);
+        assert!(
+            req.check_preconditions(Some(&Validators::of(&tagged())))
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn if_modified_since() {
+        let later = "Mon, 07 Nov 1994 08:49:37 GMT";
+        let earlier = "Sat, 05 Nov 1994 08:49:37 GMT";
+
+        assert_eq!(
+            StatusCode::NOT_MODIFIED,
+            status(&request("GET", &[("if-modified-since", DATE)]))
+        );
+        assert_eq!(
+            StatusCode::NOT_MODIFIED,
+            status(&request("GET", &[("if-modified-since", later)]))
+        );
+        assert_eq!(
+            StatusCode::OK,
+            status(&request("GET", &[("if-modified-since", earlier)]))
+        );
+
+        // If-None-Match takes precedence.
+        assert_eq!(
+            StatusCode::OK,
+            status(&request(
+                "GET",
+                &[("if-modified-since", later), ("if-none-match", r#""v2""#)]
+            ))
+        );
+    }
+
+    #[test]
+    fn if_match_and_if_unmodified_since() {
+        assert_eq!(
+            StatusCode::OK,
+            checked(&request("PUT", &[("if-match", r#""v1""#)]))
+        );
+        assert_eq!(
+            StatusCode::PRECONDITION_FAILED,
+            checked(&request("PUT", &[("if-match", r#"W/"v1""#)]))
+        );
+        assert_eq!(
+            StatusCode::PRECONDITION_FAILED,
+            checked(&request(
+                "PUT",
+                &[("if-unmodified-since", "Sat, 05 Nov 1994 08:49:37 GMT")]
+            ))
+        );
+
+        // If-Match takes precedence.
+        assert_eq!(
+            StatusCode::OK,
+            checked(&request(
+                "PUT",
+                &[
+                    ("if-match", "*"),
+                    ("if-unmodified-since", "Sat, 05 Nov 1994 08:49:37 GMT")
+                ]
+            ))
+        );
+    }
+
+    #[test]
+    fn unsafe_methods_are_not_checked_after_the_fact() {
+        let req = request("PUT", &[("if-match", r#""v0""#)]);
+
+        // The handler has already acted; a 412 now would be a lie.
+        assert_eq!(StatusCode::OK, status(&req));
+        assert_eq!(StatusCode::PRECONDITION_FAILED, checked(&req));
+    }
+
+    #[test]
+    fn star_means_a_representation_exists() {
+        let untagged = Validators::default();
+        let if_match = request("PUT", &[("if-match", "*")]);
+        let if_none_match = request("PUT", &[("if-none-match", "*")]);
+
+        assert!(if_match.check_preconditions(Some(&untagged)).is_ok());
+        assert!(if_match.check_preconditions(None).is_err());
+        assert!(if_none_match.check_preconditions(Some(&untagged)).is_err());
+        assert!(if_none_match.check_preconditions(None).is_ok());
+
+        let res = Response::new(Some("no validators"));
+        let get = request("GET", &[("if-match", "*")]);
+        assert_eq!(StatusCode::OK, preconditions(&get, res).head.status);
+    }
+}
@@ -0,0 +1,243 @@
+use crate::{
+    AcceptErrorPolicy, Cidr, Compression, Cors, SERVER_PORT, Server, TcpOptions, Timeouts,
+};
+use std::{
+    io,
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+/// Everything a [`Server`] can be tuned with. Build one with [`Server::builder`].
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Addresses [`Server::serve`] binds to.
+    pub addrs: Vec<SocketAddr>,
+    /// Upper bound on the request line plus headers, in bytes.
+    pub max_header_size: usize,
+    /// Upper bound on a request body, in bytes.
+    pub max_body_size: Option<u64>,
+    /// Whether to undo `gzip`, `deflate` and `br` `Content-Encoding` on
+    /// request bodies. Other codings get a 415 when enabled.
+    pub decompress_request_bodies: bool,
+    /// Upper bound on a request body after decompression, in bytes.
+    pub max_decompressed_body_size: Option<u64>,
+    /// Overrides `max_body_size` for paths under a prefix. The longest
+    /// matching prefix wins.
+    pub route_body_sizes: Vec<(String, Option<u64>)>,
+    /// How many connections may be open at once. Once reached, the server
+    /// stops accepting until one closes.
+    pub max_connections: Option<usize>,
+    /// Past this many open connections, new ones get an immediate 503.
+    pub soft_connection_limit: Option<usize>,
+    pub timeouts: Timeouts,
+    /// Whether a connection may serve more than one request.
+    pub keep_alive: bool,
+    /// How many requests a keep-alive connection serves before closing.
+    pub max_requests_per_connection: Option<usize>,
+    /// How long to wait for open connections after the shutdown signal.
+    pub shutdown_grace_period: Option<Duration>,
+    /// Whether to log each accepted connection, request and response.
+    pub log_requests: bool,
+    /// What to do when accepting a connection fails.
+    pub accept_error_policy: AcceptErrorPolicy,
+    /// Socket options for the addresses [`Server::serve`] binds.
+    pub tcp: TcpOptions,
+    /// Peers whose `Forwarded` and `X-Forwarded-*` headers are believed.
+    pub trusted_proxies: Vec<Cidr>,
+    /// Cross-origin policy. `None` sends no CORS headers at all.
+    pub cors: Option<Cors>,
+    /// How responses are compressed. `None`, the default, sends them as they
+    /// are. Compressing pages that reflect input next to secrets over TLS
+    /// invites BREACH; leave those uncompressed.
+    pub compression: Option<Compression>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            addrs: Vec::new(),
+            max_header_size: 8 * 1024,
+            max_body_size: Some(2 * 1024 * 1024),
+            decompress_request_bodies: false,
+            max_decompressed_body_size: Some(8 * 1024 * 1024),
+            route_body_sizes: Vec::new(),
+            max_connections: None,
+            soft_connection_limit: None,
+            timeouts: Timeouts::default(),
+            keep_alive: true,
+            max_requests_per_connection: None,
+            shutdown_grace_period: Some(Duration::from_secs(30)),
+            log_requests: true,
+            accept_error_policy: AcceptErrorPolicy::default(),
+            tcp: TcpOptions::default(),
+            trusted_proxies: Vec::new(),
+            cors: None,
+            compression: None,
+        }
+    }
+}
+
+impl Config {
+    pub(crate) fn addrs(&self) -> Vec<SocketAddr> {
+        if self.addrs.is_empty() {
+            vec![SocketAddr::from((Ipv4Addr::LOCALHOST, SERVER_PORT))]
+        } else {
+            self.addrs.clone()
+        }
+    }
+
+    /// Fails if the settings contradict each other.
+    pub(crate) fn check(&self) -> io::Result<()> {
+        self.cors.as_ref().map_or(Ok(()), Cors::check)
+    }
+
+    /// The body size limit for a request to `uri`.
+    pub fn max_body_size_for(&self, uri: &str) -> Option<u64> {
+        let path = uri.split(['?', '#']).next().unwrap_or_default();
+
+        self.route_body_sizes
+            .iter()
+            .filter(|(prefix, _)| under_prefix(path, prefix))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map_or(self.max_body_size, |(_, max)| *max)
+    }
+}
+
+/// Whether `path` is `prefix` or below it, segment-wise: `/upload` covers
+/// `/upload/a` but not `/uploads`.
+fn under_prefix(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+
+    path.strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+}
+
+#[derive(Default, Debug)]
+pub struct Builder {
+    config: Config,
+}
+
+impl Builder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds an address to listen on. Defaults to `127.0.0.1:SERVER_PORT`
+    /// if none is given.
+    pub fn bind(mut self, addr: impl Into<SocketAddr>) -> Self {
+        self.config.addrs.push(addr.into());
+        self
+    }
+
+    pub fn max_header_size(mut self, bytes: usize) -> Self {
+        self.config.max_header_size = bytes;
+        self
+    }
+
+    pub fn max_body_size(mut self, bytes: Option<u64>) -> Self {
+        self.config.max_body_size = bytes;
+        self
+    }
+
+    pub fn decompress_request_bodies(mut self, enabled: bool) -> Self {
+        self.config.decompress_request_bodies = enabled;
+        self
+    }
+
+    pub fn max_decompressed_body_size(mut self, bytes: Option<u64>) -> Self {
+        self.config.max_decompressed_body_size = bytes;
+        self
+    }
+
+    /// Sets the body size limit for paths under `prefix`, e.g. a larger one
+    /// for `/upload`.
+    pub fn max_body_size_for(mut self, prefix: impl Into<String>, bytes: Option<u64>) -> Self {
+        self.config.route_body_sizes.push((prefix.into(), bytes));
+        self
+    }
+
+    pub fn max_connections(mut self, n: Option<usize>) -> Self {
+        self.config.max_connections = n;
+        self
+    }
+
+    pub fn soft_connection_limit(mut self, n: Option<usize>) -> Self {
+        self.config.soft_connection_limit = n;
+        self
+    }
+
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.config.timeouts = timeouts;
+        self
+    }
+
+    pub fn header_read_timeout(mut self, t: Option<Duration>) -> Self {
+        self.config.timeouts.header_read = t;
+        self
+    }
+
+    pub fn body_read_timeout(mut self, t: Option<Duration>) -> Self {
+        self.config.timeouts.body_read = t;
+        self
+    }
+
+    pub fn idle_timeout(mut self, t: Option<Duration>) -> Self {
+        self.config.timeouts.idle = t;
+        self
+    }
+
+    pub fn write_timeout(mut self, t: Option<Duration>) -> Self {
+        self.config.timeouts.write = t;
+        self
+    }
+
+    pub fn keep_alive(mut self, enabled: bool) -> Self {
+        self.config.keep_alive = enabled;
+        self
+    }
+
+    pub fn max_requests_per_connection(mut self, n: Option<usize>) -> Self {
+        self.config.max_requests_per_connection = n;
+        self
+    }
+
+    pub fn shutdown_grace_period(mut self, t: Option<Duration>) -> Self {
+        self.config.shutdown_grace_period = t;
+        self
+    }
+
+    pub fn log_requests(mut self, enabled: bool) -> Self {
+        self.config.log_requests = enabled;
+        self
+    }
+
+    pub fn accept_error_policy(mut self, policy: AcceptErrorPolicy) -> Self {
+        self.config.accept_error_policy = policy;
+        self
+    }
+
+    pub fn tcp_options(mut self, options: TcpOptions) -> Self {
+        self.config.tcp = options;
+        self
+    }
+
+    /// Trusts forwarding headers from peers in `cidr`.
+    pub fn trust_proxy(mut self, cidr: Cidr) -> Self {
+        self.config.trusted_proxies.push(cidr);
+        self
+    }
+
+    pub fn cors(mut self, cors: Cors) -> Self {
+        self.config.cors = Some(cors);
+        self
+    }
+
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.config.compression = Some(compression);
+        self
+    }
+
+    pub fn build(self) -> Server {
+        Server::new(self.config)
+    }
+}
@@ -1,12 +1,27 @@
 use crate::Encode;
+use crate::HTTPParsingError;
 use crate::Headers;
+use crate::Informational;
 use crate::IntoResponse;
-use crate::{Request, Response, ServerError};
+use crate::encoder::Bodiless;
+use crate::preconditions;
+use crate::{
+    ClientInfo, Config, ContentCoding, Method, Request, Response, ServeFile, ServerError,
+    StatusCode, Validators,
+};
+use bytes::{Buf, BytesMut};
 use sha2::Digest;
 use sha2::Sha256;
 use std::fs;
-use tokio::io::AsyncWriteExt;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 use tokio::net::TcpStream;
+use tokio::sync::watch;
+use tokio::time::{self, Instant, Sleep};
 
 #[derive(PartialEq, Default)]
 pub enum ParserState {
@@ -18,66 +33,441 @@ pub enum ParserState {
     Error,
 }
 
-pub struct Connection {
-    io: TcpStream,
+/// How long a [`Connection`] waits on the peer before giving up.
+///
+/// `None` disables a timeout.
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    /// From the first byte of a request until the end of its headers.
+    pub header_read: Option<Duration>,
+    /// From the end of the headers until the end of the body.
+    pub body_read: Option<Duration>,
+    /// How long a keep-alive connection may sit between requests.
+    pub idle: Option<Duration>,
+    /// How long writing a response may go without the peer taking any of
+    /// it. Each write that gets through starts the wait again, so a slow
+    /// download runs as long as it keeps moving.
+    pub write: Option<Duration>,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            header_read: Some(Duration::from_secs(30)),
+            body_read: Some(Duration::from_secs(60)),
+            idle: Some(Duration::from_secs(60)),
+            write: Some(Duration::from_secs(60)),
+        }
+    }
+}
+
+impl Timeouts {
+    fn get(&self, phase: Phase) -> Option<Duration> {
+        match phase {
+            Phase::Idle => self.idle,
+            Phase::Head => self.header_read,
+            Phase::Body => self.body_read,
+        }
+    }
+}
+
+pub struct Connection<I = TcpStream> {
+    io: I,
+    buf: BytesMut,
     req: Request,
-    shutting_down: bool,
+    config: Arc<Config>,
+    requests: usize,
+    shutdown: Option<watch::Sender<()>>,
+    response_started: bool,
+    remote_addr: Option<SocketAddr>,
 }
 
-impl Connection {
-    pub fn new(io: TcpStream) -> Self {
+impl<I> Connection<I>
+where
+    I: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(io: I) -> Self {
         Self {
             io,
+            buf: BytesMut::with_capacity(1024),
             req: Request::new(),
-            shutting_down: false,
+            config: Arc::default(),
+            requests: 0,
+            shutdown: None,
+            response_started: false,
+            remote_addr: None,
         }
     }
 
+    pub fn with_config(mut self, config: Arc<Config>) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// The client address every request on this connection reports.
+    pub fn with_remote_addr(mut self, addr: Option<SocketAddr>) -> Self {
+        self.remote_addr = addr;
+        self
+    }
+
+    /// Drain the connection once every receiver of `signal` is dropped: the
+    /// request in flight is answered with `Connection: close`, an idle
+    /// connection is closed right away.
+    pub(crate) fn with_shutdown(mut self, signal: watch::Sender<()>) -> Self {
+        self.shutdown = Some(signal);
+        self
+    }
+
+    /// Serves requests until the peer goes away, asks to close, or errors.
     pub async fn run(&mut self) -> Result<(), ServerError> {
-        self.read().await?;
-        self.write().await?;
+        self.serve().await?;
+
+        // Lets TLS send close_notify; for TCP it's just an early FIN.
+        if let Err(err) = self.io.shutdown().await {
+            tracing::debug!("failed to shut down connection: {err}");
+        }
+
         Ok(())
     }
 
-    pub async fn graceful_shutdown(&mut self) {
-        self.shutting_down = true;
-        if let Err(err) = self.io.shutdown().await {
-            tracing::debug!("failed to close connection during shutdown: {err}");
+    async fn serve(&mut self) -> Result<(), ServerError> {
+        loop {
+            self.response_started = false;
+
+            match self.read().await {
+                Ok(true) => {}
+                Ok(false) => return Ok(()),
+                Err(err) => {
+                    tracing::info!("failed to read request: {err}");
+                    self.response_started = true;
+                    self.send(err.into_response_for(&self.req)).await?;
+
+                    return Ok(());
+                }
+            }
+
+            if !self.write().await? {
+                return Ok(());
+            }
         }
     }
 
-    async fn read(&mut self) -> Result<(), ServerError> {
-        if self.shutting_down {
+    /// Answers with `err` if no part of a response has been written yet.
+    /// Used when the request handler dies before producing a response.
+    pub async fn abort(&mut self, err: ServerError) {
+        if self.response_started {
+            return;
+        }
+
+        self.response_started = true;
+        if let Err(err) = self.send(err.into_response_for(&self.req)).await {
+            tracing::debug!("failed to send error response: {err}");
+        }
+    }
+
+    /// Reads the next request into `self.req`.
+    ///
+    /// Returns `false` if the peer closed the connection or stayed idle past
+    /// [`Timeouts::idle`] before sending anything.
+    async fn read(&mut self) -> Result<bool, ServerError> {
+        if self.shutting_down() {
+            return Ok(false);
+        }
+
+        self.req = Request::new();
+        self.req.remote_addr = self.remote_addr;
+        let mut deadline = None;
+        let mut head_len = 0;
+
+        loop {
+            let in_head = self.in_head();
+            let window = match in_head {
+                // Never hand the parser more head than we're willing to accept.
+                true => self.buf.len().min(self.config.max_header_size - head_len),
+                false => self.buf.len(),
+            };
+            let n = self.req.parse(&self.buf[..window])?;
+            self.buf.advance(n);
+
+            if self.in_head() {
+                head_len += n;
+                if head_len + self.buf.len() > self.config.max_header_size {
+                    return Err(HTTPParsingError::HeadersTooLarge.into());
+                }
+            } else if in_head {
+                self.req.max_body_size = self.config.max_body_size_for(&self.req.head.uri);
+                self.check_body_size()?;
+                self.req.coding = self.content_coding()?;
+                self.req.max_decoded_size = self.config.max_decompressed_body_size;
+                if self.expects_continue()? {
+                    // Doesn't start the response: the final one still follows.
+                    self.send(Informational::continue_()).await?;
+                }
+
+                // Some of the body may already be buffered.
+                continue;
+            }
+
+            if self.req.done() {
+                break;
+            }
+
+            let phase = match self.req.state {
+                ParserState::Init if self.buf.is_empty() => Phase::Idle,
+                ParserState::Body => Phase::Body,
+                _ => Phase::Head,
+            };
+
+            if deadline.is_none_or(|d: Deadline| d.phase != phase) {
+                deadline = self.config.timeouts.get(phase).map(|t| Deadline {
+                    phase,
+                    at: Instant::now() + t,
+                });
+            }
+
+            let read = read_until(&mut self.io, &mut self.buf, deadline.map(|d| d.at));
+            let n = tokio::select! {
+                n = read => n?,
+                _ = shutdown_signaled(&self.shutdown), if phase == Phase::Idle => {
+                    tracing::debug!("closing idle connection for shutdown");
+                    return Ok(false);
+                }
+            };
+
+            let Some(n) = n else {
+                if phase == Phase::Idle {
+                    tracing::debug!("closing idle connection");
+                    return Ok(false);
+                }
+
+                return Err(ServerError::Timeout);
+            };
+
+            if n == 0 {
+                if self.req.state == ParserState::Init && self.buf.is_empty() {
+                    return Ok(false);
+                }
+
+                return Err(HTTPParsingError::UnexpectedEof.into());
+            }
+        }
+
+        self.req.decode_body().await?;
+        self.req.client_info = ClientInfo::extract(&self.req, &self.config.trusted_proxies);
+        self.requests += 1;
+        if self.config.log_requests {
+            tracing::info!("request received:\n {:?}", self.req);
+        }
+
+        Ok(true)
+    }
+
+    fn shutting_down(&self) -> bool {
+        self.shutdown.as_ref().is_some_and(|s| s.is_closed())
+    }
+
+    fn in_head(&self) -> bool {
+        matches!(self.req.state, ParserState::Init | ParserState::Headers)
+    }
+
+    /// Rejects a declared `Content-Length` over the limit before reading any
+    /// of the body. Chunked bodies are checked as they arrive.
+    fn check_body_size(&self) -> Result<(), HTTPParsingError> {
+        let (Some(max), Some(cl)) = (
+            self.req.max_body_size,
+            self.req.head.headers.get("content-length"),
+        ) else {
             return Ok(());
+        };
+
+        if cl.parse::<u64>()? > max {
+            return Err(HTTPParsingError::BodyTooLarge);
         }
-        self.req = Request::from_reader(&mut self.io).await?;
-        tracing::info!("request received:\n {:?}", self.req);
 
         Ok(())
     }
 
-    async fn write(&mut self) -> Result<(), ServerError> {
-        if self.shutting_down {
+    /// The coding to undo on the request body, if decompression is on. A
+    /// coding we can't undo, or more than one stacked, gets a 415.
+    fn content_coding(&self) -> Result<Option<ContentCoding>, HTTPParsingError> {
+        if !self.config.decompress_request_bodies {
+            return Ok(None);
+        }
+        let Some(encoding) = self.req.head.headers.get("content-encoding") else {
+            return Ok(None);
+        };
+
+        let mut codings = encoding
+            .split(',')
+            .map(str::trim)
+            .filter(|c| !c.is_empty() && !c.eq_ignore_ascii_case("identity"));
+
+        match (codings.next(), codings.next()) {
+            (None, _) => Ok(None),
+            (Some(coding), None) => ContentCoding::from_name(coding)
+                .map(Some)
+                .ok_or(HTTPParsingError::UnsupportedContentEncoding),
+            _ => Err(HTTPParsingError::UnsupportedContentEncoding),
+        }
+    }
+
+    /// Whether the client is holding back the body until we answer
+    /// `Expect: 100-continue`. Any other expectation is refused with a 417.
+    fn expects_continue(&self) -> Result<bool, HTTPParsingError> {
+        let Some(expect) = self.req.head.headers.get("expect") else {
+            return Ok(false);
+        };
+
+        if !expect.trim().eq_ignore_ascii_case("100-continue") {
+            return Err(HTTPParsingError::ExpectationFailed);
+        }
+
+        // No point once the body is on its way.
+        Ok(self.req.state == ParserState::Body && self.buf.is_empty())
+    }
+
+    fn keep_alive(&self, res: &Response) -> bool {
+        self.config.keep_alive
+            && !self.shutting_down()
+            && self
+                .config
+                .max_requests_per_connection
+                .is_none_or(|max| self.requests < max)
+            && !has_close(self.req.head.headers.get("connection"))
+            && !has_close(res.head.headers.get("connection"))
+    }
+
+    /// Answers the current request. Returns whether the connection can be
+    /// reused for another one.
+    async fn write(&mut self) -> Result<bool, ServerError> {
+        let head = self.req.head.method == "HEAD";
+        let preflight = self
+            .config
+            .cors
+            .as_ref()
+            .and_then(|cors| cors.preflight(&self.req));
+
+        let mut res = match preflight {
+            Some(res) => res,
+            None => self.answer(head).await,
+        };
+
+        let keep_alive = self.keep_alive(&res);
+
+        if !keep_alive {
+            res.head
+                .headers
+                .replace("connection", "close".to_string())?;
+        }
+
+        self.response_started = true;
+        match head {
+            true => self.send(Bodiless(res)).await?,
+            false => self.send(res).await?,
+        }
+
+        if self.config.log_requests {
+            tracing::info!("response sent");
+        }
+
+        Ok(keep_alive)
+    }
+
+    /// Routes anything but a CORS preflight.
+    async fn answer(&mut self, head: bool) -> Response {
+        let mut res = match self.check_unsafe().await {
+            Ok(()) => Self::respond(&self.req).await,
+            Err(err) => err.into_response_for(&self.req),
+        };
+        let not_allowed = res.head.status == StatusCode::METHOD_NOT_ALLOWED;
+
+        if head && not_allowed {
+            // Nothing answers HEAD itself, so answer what GET would and leave
+            // the body off when sending.
+            self.req.head.method = Method::GET;
+            res = Self::respond(&self.req).await;
+            self.req.head.method = Method::HEAD;
+        } else if self.req.head.method == "OPTIONS" && not_allowed {
+            res = allowed_methods(res);
+        }
+
+        if let Some(cors) = &self.config.cors {
+            cors.apply(&self.req, &mut res);
+        }
+        if let Some(compression) = &self.config.compression {
+            res = compression.compress(&self.req, res).await;
+        }
+
+        // A GET or HEAD handler that sets validators gets 304s and 412s for
+        // free; other methods were checked before routing. This runs last so
+        // a 304 carries the validators the full response would, weakened or
+        // not.
+        preconditions(&self.req, res)
+    }
+
+    /// Evaluates the conditions of anything but `GET` and `HEAD` before it
+    /// is routed, against the validators a `GET` of the target carries. The
+    /// handler never runs if they fail.
+    async fn check_unsafe(&mut self) -> Result<(), ServerError> {
+        let safe = self.req.head.method == "GET" || self.req.head.method == "HEAD";
+        let conditional = ["if-match", "if-unmodified-since", "if-none-match"]
+            .iter()
+            .any(|h| self.req.head.headers.get(h).is_some());
+
+        if safe || !conditional {
             return Ok(());
         }
 
-        let r = if self.req.head.uri.as_str() == "/myproblem" {
-            Err(ServerError::Internal)
-        } else if self.req.head.uri.as_str() == "/yourproblem" {
-            Err(ServerError::BadRequest)
-        } else if self.req.head.uri.as_str() == "/video" {
-            let v = fs::read("assets/vim.mp4").unwrap();
-            let cl = v.len() as u16;
+        let method = std::mem::replace(&mut self.req.head.method, Method::GET);
+        let current = Self::respond(&self.req).await;
+        self.req.head.method = method;
 
-            let r = Response::new(Some(v))
-                .content_type("video/mp4")?
-                .content_length(cl)?;
+        let current = current
+            .head
+            .status
+            .is_success()
+            .then(|| Validators::of(&current));
+        self.req.check_preconditions(current.as_ref())
+    }
+
+    async fn respond(req: &Request) -> Response {
+        match Self::route(req).await {
+            Ok(res) => res.into_response(),
+            Err(err) => err.into_response_for(req),
+        }
+    }
 
-            Ok(r)
-        } else if self.req.head.uri.as_str().contains("/httpbin") {
+    async fn send(&mut self, res: impl Encode) -> Result<(), ServerError> {
+        async fn write<W: AsyncWrite + Unpin>(
+            io: &mut W,
+            res: impl Encode,
+        ) -> Result<(), ServerError> {
+            res.write(io).await?;
+            // Streams like TLS buffer internally; push the response out.
+            Ok(io.flush().await?)
+        }
+
+        match self.config.timeouts.write {
+            Some(t) => match write(&mut Stalled::new(&mut self.io, t), res).await {
+                Err(ServerError::IOError(err)) if err.kind() == io::ErrorKind::TimedOut => {
+                    Err(ServerError::Timeout)
+                }
+                res => res,
+            },
+            None => write(&mut self.io, res).await,
+        }
+    }
+
+    async fn route(req: &Request) -> Result<Response, ServerError> {
+        if req.head.uri.as_str() == "/myproblem" {
+            Err(ServerError::Internal)
+        } else if req.head.uri.as_str() == "/yourproblem" {
+            Err(ServerError::BadRequest)
+        } else if req.head.uri.as_str() == "/video" {
+            ServeFile::new("assets/vim.mp4").serve(req).await
+        } else if req.head.uri.as_str().contains("/httpbin") {
             let bin = reqwest::get(
-                self.req
-                    .head
+                req.head
                     .uri
                     .as_str()
                     .replace("/httpbin", "https://httpbin.org"),
@@ -85,7 +475,7 @@ impl Connection {
             .await?;
 
             let mut body = Vec::<u8>::new();
-            let bytes = bin.bytes().await.unwrap();
+            let bytes = bin.bytes().await?;
 
             for chunk in bytes.chunks(32) {
                 body.extend_from_slice(format!("{:x}\r\n", chunk.len()).as_bytes());
@@ -104,25 +494,605 @@ impl Connection {
             trailers.set("X-Content-Length".to_string(), bytes.len().to_string())?;
 
             // suboptimal. the body should probably be Bytes, too
-            let r = Response::new(Some(body))
-                .chunked()
-                .unwrap()
-                .with_sha()
-                .unwrap()
-                .set_trailers(trailers);
-
-            Ok(r)
+            Ok(Response::new(Some(body))
+                .chunked()?
+                .with_sha()?
+                .set_trailers(trailers))
         } else {
-            let r = Response::new(Some(fs::read("200.html").unwrap()))
-                .content_type("text/html")
-                .unwrap();
-            Ok(r)
+            Response::new(Some(fs::read("200.html")?)).content_type("text/html")
+        }
+    }
+}
+
+/// Turns the `405` a handler gave `OPTIONS` into the `204` that lists what
+/// it does allow.
+fn allowed_methods(res: Response) -> Response {
+    let Some(allow) = res.head.headers.get("allow") else {
+        return res;
+    };
+
+    let mut headers = Headers::new();
+    headers.replace("allow", format!("{allow}, OPTIONS")).ok();
+
+    let mut out = Response::new(Some(""));
+    out.head.status = StatusCode::NO_CONTENT;
+    out.head.headers = headers;
+    out
+}
+
+/// Which part of a request the connection is waiting on.
+#[derive(Clone, Copy, PartialEq)]
+enum Phase {
+    Idle,
+    Head,
+    Body,
+}
+
+#[derive(Clone, Copy)]
+struct Deadline {
+    phase: Phase,
+    at: Instant,
+}
+
+/// Reads into `buf`, giving up at `deadline`. `None` means timed out.
+async fn read_until<I: AsyncRead + Unpin>(
+    io: &mut I,
+    buf: &mut BytesMut,
+    deadline: Option<Instant>,
+) -> io::Result<Option<usize>> {
+    match deadline {
+        Some(at) => match time::timeout_at(at, io.read_buf(buf)).await {
+            Ok(n) => n.map(Some),
+            Err(_) => Ok(None),
+        },
+        None => io.read_buf(buf).await.map(Some),
+    }
+}
+
+/// A writer that fails with `TimedOut` once the peer has taken nothing for
+/// `timeout`. The wait starts over whenever a write or flush gets through.
+struct Stalled<'a, W> {
+    io: &'a mut W,
+    timeout: Duration,
+    sleep: Pin<Box<Sleep>>,
+}
+
+impl<'a, W> Stalled<'a, W> {
+    fn new(io: &'a mut W, timeout: Duration) -> Self {
+        Self {
+            io,
+            timeout,
+            sleep: Box::pin(time::sleep(timeout)),
+        }
+    }
+
+    fn progress<T>(
+        &mut self,
+        cx: &mut Context<'_>,
+        poll: Poll<io::Result<T>>,
+    ) -> Poll<io::Result<T>> {
+        match poll {
+            Poll::Ready(res) => {
+                let deadline = Instant::now() + self.timeout;
+                self.sleep.as_mut().reset(deadline);
+                Poll::Ready(res)
+            }
+            Poll::Pending => match self.sleep.as_mut().poll(cx) {
+                Poll::Ready(()) => Poll::Ready(Err(io::ErrorKind::TimedOut.into())),
+                Poll::Pending => Poll::Pending,
+            },
+        }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Stalled<'_, W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut *this.io).poll_write(cx, buf);
+        this.progress(cx, poll)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut *this.io).poll_flush(cx);
+        this.progress(cx, poll)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut *this.io).poll_shutdown(cx);
+        this.progress(cx, poll)
+    }
+}
+
+async fn shutdown_signaled(signal: &Option<watch::Sender<()>>) {
+    match signal {
+        Some(signal) => signal.closed().await,
+        None => std::future::pending().await,
+    }
+}
+
+fn has_close(connection: Option<&String>) -> bool {
+    connection.is_some_and(|v| v.split(',').any(|t| t.trim().eq_ignore_ascii_case("close")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Cors;
+    use tokio::net::TcpListener;
+
+    async fn serve_one(config: Config) -> TcpStream {
+        let (client, mut conn) = connect(config).await;
+
+        tokio::spawn(async move {
+            let _ = conn.run().await;
+        });
+
+        client
+    }
+
+    async fn connect(config: Config) -> (TcpStream, Connection) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (io, _) = listener.accept().await.unwrap();
+
+        (client, Connection::new(io).with_config(Arc::new(config)))
+    }
+
+    fn short(d: Duration) -> Config {
+        Config {
+            timeouts: Timeouts {
+                header_read: Some(d),
+                body_read: Some(d),
+                idle: Some(d),
+                write: Some(d),
+            },
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_headers_get_408() {
+        let mut client = serve_one(short(Duration::from_millis(50))).await;
+
+        client
+            .write_all(b"GET / HTTP/1.1\r\nHost: x")
+            .await
+            .unwrap();
+
+        let mut res = String::new();
+        client.read_to_string(&mut res).await.unwrap();
+
+        assert!(res.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+        assert!(res.contains("connection: close\r\n"));
+    }
+
+    #[tokio::test]
+    async fn slow_body_gets_408() {
+        let mut client = serve_one(short(Duration::from_millis(50))).await;
+
+        client
+            .write_all(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc")
+            .await
+            .unwrap();
+
+        let mut res = String::new();
+        client.read_to_string(&mut res).await.unwrap();
+
+        assert!(res.starts_with("HTTP/1.1 408 Request Timeout\r\n"));
+    }
+
+    #[tokio::test]
+    async fn slow_steady_reader_outlasts_the_write_timeout() {
+        let (mut client, io) = tokio::io::duplex(1024);
+        let mut conn = Connection::new(io).with_config(Arc::new(short(Duration::from_millis(50))));
+
+        // 64 reads, 5ms apart: far past the timeout, but never 50ms idle.
+        let reader = tokio::spawn(async move {
+            let mut got = Vec::new();
+            let mut buf = [0u8; 1024];
+            loop {
+                time::sleep(Duration::from_millis(5)).await;
+                match client.read(&mut buf).await.unwrap() {
+                    0 => return got,
+                    n => got.extend_from_slice(&buf[..n]),
+                }
+            }
+        });
+
+        let body = vec![b'x'; 64 * 1024];
+        conn.send(Response::new(Some(body.clone()))).await.unwrap();
+        drop(conn);
+
+        assert!(reader.await.unwrap().ends_with(&body));
+    }
+
+    #[tokio::test]
+    async fn stalled_reader_hits_the_write_timeout() {
+        let (_client, io) = tokio::io::duplex(1024);
+        let mut conn = Connection::new(io).with_config(Arc::new(short(Duration::from_millis(50))));
+
+        let res = conn.send(Response::new(Some(vec![b'x'; 4096]))).await;
+        assert!(matches!(res, Err(ServerError::Timeout)));
+    }
+
+    #[tokio::test]
+    async fn idle_connection_is_closed_silently() {
+        let mut client = serve_one(short(Duration::from_millis(50))).await;
+
+        let mut res = Vec::new();
+        client.read_to_end(&mut res).await.unwrap();
+
+        assert!(res.is_empty());
+    }
+
+    #[tokio::test]
+    async fn keep_alive_serves_pipelined_requests() {
+        let mut client = serve_one(Config::default()).await;
+
+        client
+            .write_all(b"GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut res = String::new();
+        client.read_to_string(&mut res).await.unwrap();
+
+        assert_eq!(2, res.matches("HTTP/1.1 200 OK\r\n").count());
+    }
+
+    #[tokio::test]
+    async fn pipelined_responses_end_at_their_content_length() {
+        let mut client = serve_one(Config::default()).await;
+
+        client
+            .write_all(b"GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut res = String::new();
+        client.read_to_string(&mut res).await.unwrap();
+        let page = std::fs::read_to_string("200.html").unwrap();
+
+        let second = res.rfind("HTTP/1.1 200 OK\r\n").unwrap();
+        assert!(res[..second].ends_with(&page));
+        assert!(res.ends_with(&page));
+    }
+
+    #[tokio::test]
+    async fn oversized_head_gets_431() {
+        let mut client = serve_one(Config {
+            max_header_size: 32,
+            ..Default::default()
+        })
+        .await;
+
+        client
+            .write_all(b"GET / HTTP/1.1\r\nUser-Agent: a-rather-long-user-agent\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut res = String::new();
+        client.read_to_string(&mut res).await.unwrap();
+
+        assert!(res.starts_with("HTTP/1.1 431 Request Header Fields Too Large\r\n"));
+    }
+
+    #[tokio::test]
+    async fn oversized_body_gets_413() {
+        let mut client = serve_one(Config {
+            max_body_size: Some(4),
+            ..Default::default()
+        })
+        .await;
+
+        client
+            .write_all(b"POST / HTTP/1.1\r\nContent-Length: 5\r\n\r\nhello")
+            .await
+            .unwrap();
+
+        let mut res = String::new();
+        client.read_to_string(&mut res).await.unwrap();
+
+        assert!(res.starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+    }
+
+    #[tokio::test]
+    async fn oversized_chunked_body_gets_413_mid_stream() {
+        let mut client = serve_one(Config {
+            max_body_size: Some(8),
+            ..Default::default()
+        })
+        .await;
+
+        // The second chunk goes over; the rest is never sent.
+        client
+            .write_all(b"POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n5\r\nhello\r\n5\r\n")
+            .await
+            .unwrap();
+
+        let mut res = String::new();
+        client.read_to_string(&mut res).await.unwrap();
+
+        assert!(res.starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+    }
+
+    #[tokio::test]
+    async fn route_body_size_overrides_global() {
+        let config = Config {
+            max_body_size: Some(4),
+            route_body_sizes: vec![("/upload".to_string(), Some(16))],
+            ..Default::default()
         };
+        assert_eq!(Some(16), config.max_body_size_for("/upload/a?x=1"));
+        assert_eq!(Some(4), config.max_body_size_for("/uploads"));
 
-        r.into_response().write(&mut self.io).await.unwrap();
+        let mut client = serve_one(config).await;
 
-        tracing::info!("response sent");
+        client
+            .write_all(b"POST /upload HTTP/1.1\r\nContent-Length: 10\r\n\r\n0123456789")
+            .await
+            .unwrap();
+        client
+            .write_all(b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n0123456789")
+            .await
+            .unwrap();
 
-        Ok(())
+        let mut res = String::new();
+        client.read_to_string(&mut res).await.unwrap();
+
+        assert!(!res.starts_with("HTTP/1.1 413"));
+        assert!(res.contains("HTTP/1.1 413 Content Too Large\r\n"));
+    }
+
+    #[tokio::test]
+    async fn stale_if_match_stops_put_before_the_handler() {
+        async fn put(if_match: &str) -> String {
+            let mut client = serve_one(Config::default()).await;
+            let req = format!(
+                "PUT / HTTP/1.1\r\nIf-Match: {if_match}\r\nContent-Length: 5\r\n\
+                 Connection: close\r\n\r\nhello"
+            );
+            client.write_all(req.as_bytes()).await.unwrap();
+
+            let mut res = String::new();
+            client.read_to_string(&mut res).await.unwrap();
+            res
+        }
+
+        // `/` takes any PUT and answers 200; a stale tag stops it first.
+        assert!(
+            put("\"stale\"")
+                .await
+                .starts_with("HTTP/1.1 412 Precondition Failed\r\n")
+        );
+        assert!(put("*").await.starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[tokio::test]
+    async fn expect_continue_gets_100_before_body() {
+        let mut client = serve_one(Config::default()).await;
+
+        client
+            .write_all(
+                b"POST / HTTP/1.1\r\nContent-Length: 5\r\nExpect: 100-continue\r\nConnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let mut interim = [0u8; 25];
+        client.read_exact(&mut interim).await.unwrap();
+        assert_eq!(b"HTTP/1.1 100 Continue\r\n\r\n", &interim);
+
+        client.write_all(b"hello").await.unwrap();
+
+        let mut res = String::new();
+        client.read_to_string(&mut res).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+    }
+
+    #[tokio::test]
+    async fn head_gets_get_headers_without_body() {
+        let mut client = serve_one(Config::default()).await;
+
+        client
+            .write_all(b"HEAD / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut res = String::new();
+        client.read_to_string(&mut res).await.unwrap();
+        let len = std::fs::metadata("200.html").unwrap().len();
+
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(res.contains(&format!("content-length: {len}\r\n")));
+        assert!(res.ends_with("\r\n\r\n"));
+    }
+
+    #[tokio::test]
+    async fn options_lists_allowed_methods() {
+        let mut client = serve_one(Config::default()).await;
+
+        client
+            .write_all(b"OPTIONS /video HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut res = String::new();
+        client.read_to_string(&mut res).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(res.contains("allow: GET, HEAD, OPTIONS\r\n"));
+    }
+
+    #[tokio::test]
+    async fn cors_preflight_is_answered() {
+        let mut client = serve_one(Config {
+            cors: Some(Cors::new().allow_origin("https://app.example.com")),
+            ..Default::default()
+        })
+        .await;
+
+        client
+            .write_all(
+                b"OPTIONS /api HTTP/1.1\r\nOrigin: https://app.example.com\r\n\
+                  Access-Control-Request-Method: POST\r\n\r\n\
+                  GET / HTTP/1.1\r\nOrigin: https://app.example.com\r\nConnection: close\r\n\r\n",
+            )
+            .await
+            .unwrap();
+
+        let mut res = String::new();
+        client.read_to_string(&mut res).await.unwrap();
+        let (preflight, actual) = res.split_at(res.find("HTTP/1.1 200").unwrap());
+
+        assert!(preflight.starts_with("HTTP/1.1 204 No Content\r\n"));
+        assert!(preflight.contains("access-control-allow-origin: https://app.example.com\r\n"));
+        assert!(preflight.contains("access-control-allow-methods: GET, HEAD, POST\r\n"));
+        assert!(actual.contains("access-control-allow-origin: https://app.example.com\r\n"));
+        assert!(actual.contains("vary: origin\r\n"));
+    }
+
+    #[tokio::test]
+    async fn unsupported_content_encoding_gets_415() {
+        let mut client = serve_one(Config {
+            decompress_request_bodies: true,
+            ..Default::default()
+        })
+        .await;
+
+        client
+            .write_all(b"POST / HTTP/1.1\r\nContent-Encoding: zstd\r\nContent-Length: 3\r\n\r\nabc")
+            .await
+            .unwrap();
+
+        let mut res = String::new();
+        client.read_to_string(&mut res).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 415 Unsupported Media Type\r\n"));
+    }
+
+    #[tokio::test]
+    async fn not_modified_has_no_body() {
+        let mut res = Response::new(Some("stale"));
+        res.head.status = StatusCode::NOT_MODIFIED;
+
+        let mut out = Vec::new();
+        res.write(&mut out).await.unwrap();
+
+        assert!(out.ends_with(b"\r\n\r\n"));
+        assert!(!out.ends_with(b"stale"));
+    }
+
+    #[tokio::test]
+    async fn no_content_has_no_content_length() {
+        for res in [
+            StatusCode::NO_CONTENT.into_response(),
+            (StatusCode::NO_CONTENT, ()).into_response(),
+        ] {
+            assert_eq!("0", res.head.headers.get("content-length").unwrap());
+
+            let mut out = Vec::new();
+            res.write(&mut out).await.unwrap();
+            let out = String::from_utf8(out).unwrap();
+
+            assert!(out.starts_with("HTTP/1.1 204 No Content\r\n"));
+            assert!(!out.contains("content-length"));
+            assert!(out.ends_with("\r\n\r\n"));
+        }
+    }
+
+    #[tokio::test]
+    async fn expect_continue_with_oversized_body_gets_413() {
+        let mut client = serve_one(Config {
+            max_body_size: Some(4),
+            ..Default::default()
+        })
+        .await;
+
+        client
+            .write_all(b"POST / HTTP/1.1\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut res = String::new();
+        client.read_to_string(&mut res).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 413 Content Too Large\r\n"));
+    }
+
+    #[tokio::test]
+    async fn unknown_expectation_gets_417() {
+        let mut client = serve_one(Config::default()).await;
+
+        client
+            .write_all(b"POST / HTTP/1.1\r\nContent-Length: 5\r\nExpect: tea\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut res = String::new();
+        client.read_to_string(&mut res).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 417 Expectation Failed\r\n"));
+    }
+
+    #[tokio::test]
+    async fn keep_alive_disabled_closes_after_one_request() {
+        let mut client = serve_one(Config {
+            keep_alive: false,
+            ..Default::default()
+        })
+        .await;
+
+        client
+            .write_all(b"GET / HTTP/1.1\r\n\r\nGET / HTTP/1.1\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut res = String::new();
+        client.read_to_string(&mut res).await.unwrap();
+
+        assert_eq!(1, res.matches("HTTP/1.1 200 OK\r\n").count());
+        assert!(res.contains("connection: close\r\n"));
+    }
+
+    #[tokio::test]
+    async fn shutdown_finishes_in_flight_request() {
+        let (signal_tx, signal_rx) = watch::channel(());
+        let (mut client, conn) = connect(Config::default()).await;
+        let mut conn = conn.with_shutdown(signal_tx);
+
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        let task = tokio::spawn(async move { conn.run().await });
+
+        time::sleep(Duration::from_millis(20)).await;
+        drop(signal_rx);
+        client.write_all(b"\r\n").await.unwrap();
+
+        let mut res = String::new();
+        client.read_to_string(&mut res).await.unwrap();
+
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(res.contains("connection: close\r\n"));
+        assert!(task.await.unwrap().is_ok());
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_idle_connection() {
+        let (signal_tx, signal_rx) = watch::channel(());
+        let (mut client, conn) = connect(Config::default()).await;
+        let mut conn = conn.with_shutdown(signal_tx);
+
+        let task = tokio::spawn(async move { conn.run().await });
+        drop(signal_rx);
+
+        let mut res = Vec::new();
+        client.read_to_end(&mut res).await.unwrap();
+
+        assert!(res.is_empty());
+        assert!(task.await.unwrap().is_ok());
     }
 }
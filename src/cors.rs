@@ -0,0 +1,417 @@
+use crate::{Headers, Request, Response, StatusCode};
+use std::{fmt, io, sync::Arc, time::Duration};
+
+type OriginCheck = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+
+const ANY_WITH_CREDENTIALS: &str =
+    "allowing any origin with credentials would let every site read credentialed responses";
+
+/// Which origins may read responses cross-origin.
+#[derive(Clone, Default)]
+pub enum AllowOrigin {
+    /// None: CORS headers are never sent.
+    #[default]
+    None,
+    /// Any origin, answered with `*`. Browsers never share credentialed
+    /// responses with `*`, and echoing every origin instead would let any
+    /// site read them, so this can't be combined with credentials.
+    Any,
+    /// These origins exactly, e.g. `https://app.example.com`.
+    List(Vec<String>),
+    /// Origins for which the function returns true.
+    Predicate(OriginCheck),
+}
+
+impl AllowOrigin {
+    fn allows(&self, origin: &str) -> bool {
+        // Sandboxed frames, `file:` pages and redirects all send `null`, so
+        // it only counts when listed by name.
+        if origin == "null" && !matches!(self, Self::List(_)) {
+            return false;
+        }
+
+        match self {
+            Self::None => false,
+            Self::Any => true,
+            Self::List(list) => list.iter().any(|o| o.eq_ignore_ascii_case(origin)),
+            Self::Predicate(f) => f(origin),
+        }
+    }
+}
+
+impl fmt::Debug for AllowOrigin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::None => f.write_str("None"),
+            Self::Any => f.write_str("Any"),
+            Self::List(list) => f.debug_tuple("List").field(list).finish(),
+            Self::Predicate(_) => f.write_str("Predicate"),
+        }
+    }
+}
+
+/// Cross-origin resource sharing: answers preflight `OPTIONS` requests and
+/// adds `Access-Control-*` headers for allowed origins. Set it with
+/// [`Builder::cors`](crate::Builder::cors).
+///
+/// Nothing is allowed until [`Cors::allow_origin`] or one of its siblings
+/// is called.
+#[derive(Clone, Debug)]
+pub struct Cors {
+    origins: AllowOrigin,
+    methods: Vec<String>,
+    headers: Vec<String>,
+    credentials: bool,
+    expose: Vec<String>,
+    max_age: Option<Duration>,
+}
+
+impl Default for Cors {
+    fn default() -> Self {
+        Self {
+            origins: AllowOrigin::None,
+            methods: ["GET", "HEAD", "POST"].map(String::from).to_vec(),
+            headers: Vec::new(),
+            credentials: false,
+            expose: Vec::new(),
+            max_age: None,
+        }
+    }
+}
+
+impl Cors {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows `origin` exactly, adding to any allowed before.
+    pub fn allow_origin(mut self, origin: impl Into<String>) -> Self {
+        match &mut self.origins {
+            AllowOrigin::List(list) => list.push(origin.into()),
+            origins => *origins = AllowOrigin::List(vec![origin.into()]),
+        }
+        self
+    }
+
+    /// Allows every origin. Serving fails if credentials are allowed too.
+    pub fn allow_any_origin(mut self) -> Self {
+        self.origins = AllowOrigin::Any;
+        self
+    }
+
+    /// Allows origins for which `f` returns true, e.g. any subdomain.
+    pub fn allow_origin_fn<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.origins = AllowOrigin::Predicate(Arc::new(f));
+        self
+    }
+
+    /// Methods preflights may ask for. Defaults to `GET`, `HEAD` and `POST`.
+    pub fn allow_methods<S: Into<String>>(mut self, methods: impl IntoIterator<Item = S>) -> Self {
+        self.methods = methods.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Request headers preflights may ask for, beyond the CORS-safelisted ones.
+    pub fn allow_headers<S: Into<String>>(mut self, headers: impl IntoIterator<Item = S>) -> Self {
+        self.headers = headers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Whether browsers may send cookies and read responses to credentialed
+    /// requests. Serving fails if any origin is allowed too.
+    pub fn allow_credentials(mut self, allow: bool) -> Self {
+        self.credentials = allow;
+        self
+    }
+
+    /// Response headers scripts may read, beyond the CORS-safelisted ones.
+    pub fn expose_headers<S: Into<String>>(mut self, headers: impl IntoIterator<Item = S>) -> Self {
+        self.expose = headers.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// How long browsers may cache a preflight answer.
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Fails if the policy can't be served safely, which is when any origin
+    /// is allowed along with credentials.
+    pub(crate) fn check(&self) -> io::Result<()> {
+        match self.credentials && matches!(self.origins, AllowOrigin::Any) {
+            true => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                ANY_WITH_CREDENTIALS,
+            )),
+            false => Ok(()),
+        }
+    }
+
+    /// Answers `req` if it's a preflight: an `OPTIONS` with `Origin` and
+    /// `Access-Control-Request-Method`. A disallowed preflight still gets a
+    /// `204`, just without the headers that would let the browser go on.
+    pub fn preflight(&self, req: &Request) -> Option<Response> {
+        let headers = &req.head.headers;
+        if req.head.method != "OPTIONS" {
+            return None;
+        }
+        let origin = headers.get("origin")?;
+        let method = headers.get("access-control-request-method")?;
+
+        let mut res = Response::new(Some(""));
+        res.head.status = StatusCode::NO_CONTENT;
+        res.head.headers = Headers::new();
+        for name in [
+            "origin",
+            "access-control-request-method",
+            "access-control-request-headers",
+        ] {
+            res.head.headers.add_vary(name);
+        }
+
+        let method_allowed = self
+            .methods
+            .iter()
+            .any(|m| m.eq_ignore_ascii_case(method.trim()));
+        let headers_allowed =
+            headers
+                .get("access-control-request-headers")
+                .is_none_or(|requested| {
+                    requested
+                        .split(',')
+                        .map(str::trim)
+                        .filter(|h| !h.is_empty())
+                        .all(|h| self.headers.iter().any(|a| a.eq_ignore_ascii_case(h)))
+                });
+        if !self.origins.allows(origin) || !method_allowed || !headers_allowed {
+            return Some(res);
+        }
+
+        let out = &mut res.head.headers;
+        self.allow(origin, out);
+        out.replace("access-control-allow-methods", self.methods.join(", "))
+            .ok();
+        if !self.headers.is_empty() {
+            out.replace("access-control-allow-headers", self.headers.join(", "))
+                .ok();
+        }
+        if let Some(max_age) = self.max_age {
+            out.replace("access-control-max-age", max_age.as_secs().to_string())
+                .ok();
+        }
+
+        Some(res)
+    }
+
+    /// Adds the CORS headers for `req`'s origin to an actual (non-preflight)
+    /// response.
+    pub fn apply(&self, req: &Request, res: &mut Response) {
+        let headers = &mut res.head.headers;
+        if self.varies() {
+            headers.add_vary("origin");
+        }
+
+        let Some(origin) = req.head.headers.get("origin") else {
+            return;
+        };
+        if !self.origins.allows(origin) {
+            return;
+        }
+
+        self.allow(origin, headers);
+        if !self.expose.is_empty() {
+            headers
+                .replace("access-control-expose-headers", self.expose.join(", "))
+                .ok();
+        }
+    }
+
+    fn allow(&self, origin: &str, headers: &mut Headers) {
+        let value = match self.varies() {
+            true => origin.to_string(),
+            false => "*".to_string(),
+        };
+        headers.replace("access-control-allow-origin", value).ok();
+
+        if self.credentials {
+            headers
+                .replace("access-control-allow-credentials", "true".to_string())
+                .ok();
+        }
+    }
+
+    /// Whether responses depend on the request's `Origin`. Only `*` doesn't.
+    fn varies(&self) -> bool {
+        !matches!(self.origins, AllowOrigin::None | AllowOrigin::Any)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Method;
+
+    fn request(method: Method, headers: &[(&str, &str)]) -> Request {
+        let mut req = Request::new();
+        req.head.method = method;
+        for (k, v) in headers {
+            req.head.headers.replace(k, v.to_string()).unwrap();
+        }
+        req
+    }
+
+    fn preflight(origin: &str, method: &str, headers: Option<&str>) -> Request {
+        let mut req = request(
+            Method::OPTIONS,
+            &[
+                ("origin", origin),
+                ("access-control-request-method", method),
+            ],
+        );
+        if let Some(headers) = headers {
+            req.head
+                .headers
+                .replace("access-control-request-headers", headers.to_string())
+                .unwrap();
+        }
+        req
+    }
+
+    fn header<'a>(res: &'a Response, name: &str) -> Option<&'a str> {
+        res.head.headers.get(name).map(String::as_str)
+    }
+
+    #[test]
+    fn answers_allowed_preflights() {
+        let cors = Cors::new()
+            .allow_origin("https://app.example.com")
+            .allow_methods(["GET", "PUT"])
+            .allow_headers(["content-type", "x-token"])
+            .allow_credentials(true)
+            .max_age(Duration::from_secs(600));
+
+        let res = cors
+            .preflight(&preflight(
+                "https://app.example.com",
+                "PUT",
+                Some("X-Token, content-type"),
+            ))
+            .unwrap();
+        assert_eq!(StatusCode::NO_CONTENT, res.head.status);
+        assert_eq!(
+            Some("https://app.example.com"),
+            header(&res, "access-control-allow-origin")
+        );
+        assert_eq!(
+            Some("GET, PUT"),
+            header(&res, "access-control-allow-methods")
+        );
+        assert_eq!(
+            Some("content-type, x-token"),
+            header(&res, "access-control-allow-headers")
+        );
+        assert_eq!(
+            Some("true"),
+            header(&res, "access-control-allow-credentials")
+        );
+        assert_eq!(Some("600"), header(&res, "access-control-max-age"));
+        assert_eq!(
+            Some("origin,access-control-request-method,access-control-request-headers"),
+            header(&res, "vary")
+        );
+
+        for req in [
+            preflight("https://evil.example", "PUT", None),
+            preflight("https://app.example.com", "DELETE", None),
+            preflight("https://app.example.com", "GET", Some("x-other")),
+        ] {
+            let res = cors.preflight(&req).unwrap();
+            assert_eq!(None, header(&res, "access-control-allow-origin"));
+        }
+
+        // A plain OPTIONS isn't a preflight.
+        assert!(cors.preflight(&request(Method::OPTIONS, &[])).is_none());
+    }
+
+    #[test]
+    fn adds_headers_to_actual_responses() {
+        let cors = Cors::new()
+            .allow_origin_fn(|o| o.ends_with(".example.com"))
+            .expose_headers(["etag"]);
+        let req = request(Method::GET, &[("origin", "https://a.example.com")]);
+
+        let mut res = Response::new(Some("ok"));
+        res.head
+            .headers
+            .replace("vary", "accept-encoding".to_string())
+            .unwrap();
+        cors.apply(&req, &mut res);
+
+        assert_eq!(
+            Some("https://a.example.com"),
+            header(&res, "access-control-allow-origin")
+        );
+        assert_eq!(Some("etag"), header(&res, "access-control-expose-headers"));
+        assert_eq!(Some("accept-encoding,origin"), header(&res, "vary"));
+
+        // Disallowed origins get no CORS headers, but caches still learn
+        // the answer depends on the origin.
+        let mut res = Response::new(Some("ok"));
+        cors.apply(
+            &request(Method::GET, &[("origin", "https://evil.com")]),
+            &mut res,
+        );
+        assert_eq!(None, header(&res, "access-control-allow-origin"));
+        assert_eq!(Some("origin"), header(&res, "vary"));
+    }
+
+    #[test]
+    fn any_origin() {
+        let req = request(Method::GET, &[("origin", "https://x.com")]);
+
+        let mut res = Response::new(Some("ok"));
+        Cors::new().allow_any_origin().apply(&req, &mut res);
+        assert_eq!(Some("*"), header(&res, "access-control-allow-origin"));
+        assert_eq!(None, header(&res, "vary"));
+
+        let mut res = Response::new(Some("ok"));
+        Cors::new()
+            .allow_any_origin()
+            .apply(&request(Method::GET, &[("origin", "null")]), &mut res);
+        assert_eq!(None, header(&res, "access-control-allow-origin"));
+    }
+
+    #[test]
+    fn any_origin_with_credentials_is_rejected() {
+        assert!(Cors::new().allow_any_origin().check().is_ok());
+        assert!(Cors::new().allow_credentials(true).check().is_ok());
+
+        for cors in [
+            Cors::new().allow_credentials(true).allow_any_origin(),
+            Cors::new().allow_any_origin().allow_credentials(true),
+        ] {
+            let err = cors.check().unwrap_err();
+            assert_eq!(io::ErrorKind::InvalidInput, err.kind());
+        }
+    }
+
+    #[test]
+    fn null_origin_only_when_listed() {
+        let req = request(Method::GET, &[("origin", "null")]);
+
+        let mut res = Response::new(Some("ok"));
+        Cors::new()
+            .allow_origin_fn(|_| true)
+            .allow_credentials(true)
+            .apply(&req, &mut res);
+        assert_eq!(None, header(&res, "access-control-allow-origin"));
+
+        let mut res = Response::new(Some("ok"));
+        Cors::new().allow_origin("null").apply(&req, &mut res);
+        assert_eq!(Some("null"), header(&res, "access-control-allow-origin"));
+    }
+}
@@ -1,54 +1,213 @@
-use crate::response::Parts;
-use crate::{Headers, Response, ServerError, StatusCode, Version};
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
+use crate::compression::Compressor;
+use crate::response::{Body, Informational, Parts, Segment};
+use crate::{ContentCoding, Headers, Response, ServerError, StatusCode, Version};
+use std::io::{self, SeekFrom};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt},
+};
 
 pub trait Encode {
-    fn write(&self, w: &mut TcpStream) -> impl Future<Output = Result<(), ServerError>>;
+    fn write<W>(&self, w: &mut W) -> impl Future<Output = Result<(), ServerError>>
+    where
+        W: AsyncWrite + Unpin;
 }
 
 impl Encode for Response {
-    async fn write(&self, w: &mut TcpStream) -> Result<(), ServerError> {
+    async fn write<W>(&self, w: &mut W) -> Result<(), ServerError>
+    where
+        W: AsyncWrite + Unpin,
+    {
         self.head.write(w).await?;
-        w.write_all(&self.body).await?;
-        self.trailers.write(w).await?;
+        if self.head.status.permits_body() {
+            self.body.write(w).await?;
+            // Only a chunked body ends in a trailer section; after a
+            // Content-Length body its blank line would open the next response.
+            if is_chunked(&self.head.headers) {
+                self.trailers.write(w).await?;
+            }
+        }
 
         Ok(())
     }
 }
 
-impl Encode for Parts {
-    async fn write(&self, w: &mut TcpStream) -> Result<(), ServerError> {
-        self.status.write(w).await?;
-        self.headers.write(w).await?;
+fn is_chunked(headers: &Headers) -> bool {
+    headers
+        .get("transfer-encoding")
+        .and_then(|te| te.rsplit(',').next())
+        .is_some_and(|t| t.trim().eq_ignore_ascii_case("chunked"))
+}
+
+/// A response to `HEAD`: the head a `GET` would get, `Content-Length` and
+/// all, but neither the body nor the trailers.
+pub(crate) struct Bodiless(pub(crate) Response);
+
+impl Encode for Bodiless {
+    async fn write<W>(&self, w: &mut W) -> Result<(), ServerError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        self.0.head.write(w).await
+    }
+}
+
+impl Encode for Body {
+    async fn write<W>(&self, w: &mut W) -> Result<(), ServerError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        for segment in self.segments() {
+            let (file, offset, len) = match segment {
+                Segment::Bytes(bytes) => {
+                    w.write_all(bytes).await?;
+                    continue;
+                }
+                Segment::File { file, offset, len } => (file, offset, len),
+                Segment::Compressed { body, coding } => {
+                    write_compressed(body, coding, w).await?;
+                    continue;
+                }
+            };
+
+            // Encoding only borrows the body, so read through a handle of our own.
+            let mut file = File::from_std(file.try_clone()?);
+            file.seek(SeekFrom::Start(offset)).await?;
+
+            let copied = tokio::io::copy(&mut file.take(len), w).await?;
+            if copied < len {
+                // The file shrank since we promised its length in the head.
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+        }
 
         Ok(())
     }
 }
 
-impl Encode for StatusCode {
-    async fn write(&self, w: &mut TcpStream) -> Result<(), ServerError> {
-        match self {
-            &StatusCode::OK => Ok(w.write_all("HTTP/1.1 200 OK\r\n".as_bytes()).await?),
-            &StatusCode::BAD_REQUEST => Ok(w
-                .write_all("HTTP/1.1 400 Bad Request\r\n".as_bytes())
-                .await?),
-            &StatusCode::INTERNAL_SERVER_ERROR => Ok(w
-                .write_all("HTTP/1.1 500 Internal Server Error\r\n".as_bytes())
-                .await?),
-            _ => Err(ServerError::Internal),
+/// Writes `body` compressed with `coding`, as chunks of whatever the
+/// compressor has ready, ending with the last chunk. The trailers follow.
+async fn write_compressed<W>(
+    body: &Body,
+    coding: ContentCoding,
+    w: &mut W,
+) -> Result<(), ServerError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut compressor = Compressor::new(coding);
+    let mut buf = vec![0; 16 * 1024];
+
+    for segment in body.segments() {
+        match segment {
+            Segment::Bytes(bytes) => write_chunk(&compressor.write(bytes)?, w).await?,
+            Segment::File { file, offset, len } => {
+                let mut file = File::from_std(file.try_clone()?);
+                file.seek(SeekFrom::Start(offset)).await?;
+                let mut file = file.take(len);
+
+                let mut copied = 0;
+                loop {
+                    let n = file.read(&mut buf).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    copied += n as u64;
+                    write_chunk(&compressor.write(&buf[..n])?, w).await?;
+                }
+                if copied < len {
+                    return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+                }
+            }
+            Segment::Compressed { .. } => {
+                return Err(io::Error::new(io::ErrorKind::Unsupported, "compressed twice").into());
+            }
         }
     }
+
+    write_chunk(&compressor.finish()?, w).await?;
+    Ok(w.write_all(b"0\r\n").await?)
+}
+
+/// Writes `data` as one chunk. Empty data writes nothing, since an empty
+/// chunk would end the body.
+async fn write_chunk<W>(data: &[u8], w: &mut W) -> io::Result<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    if data.is_empty() {
+        return Ok(());
+    }
+
+    w.write_all(format!("{:x}\r\n", data.len()).as_bytes())
+        .await?;
+    w.write_all(data).await?;
+    w.write_all(b"\r\n").await
+}
+
+impl Encode for Informational {
+    async fn write<W>(&self, w: &mut W) -> Result<(), ServerError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        write_head(&self.status, &self.headers, w).await
+    }
+}
+
+impl Encode for Parts {
+    async fn write<W>(&self, w: &mut W) -> Result<(), ServerError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        write_head(&self.status, &self.headers, w).await
+    }
+}
+
+/// Writes the status line and headers. A `1xx` or `204` never has a body,
+/// so it mustn't describe one either: `Content-Length` and
+/// `Transfer-Encoding` are left out.
+async fn write_head<W>(status: &StatusCode, headers: &Headers, w: &mut W) -> Result<(), ServerError>
+where
+    W: AsyncWrite + Unpin,
+{
+    status.write(w).await?;
+
+    let unframed = status.is_informational() || *status == StatusCode::NO_CONTENT;
+    for (h, v) in &headers.0 {
+        if unframed && (h == "content-length" || h == "transfer-encoding") {
+            continue;
+        }
+        w.write_all(format!("{}: {}\r\n", h, v).as_bytes()).await?;
+    }
+
+    Ok(w.write_all(b"\r\n").await?)
+}
+
+impl Encode for StatusCode {
+    async fn write<W>(&self, w: &mut W) -> Result<(), ServerError>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let line = format!("{} {}\r\n", Version::HTTP_11.as_str(), self);
+
+        Ok(w.write_all(line.as_bytes()).await?)
+    }
 }
 
 impl Encode for Version {
-    async fn write(&self, w: &mut TcpStream) -> Result<(), ServerError> {
+    async fn write<W>(&self, w: &mut W) -> Result<(), ServerError>
+    where
+        W: AsyncWrite + Unpin,
+    {
         Ok(w.write_all(self.as_str().as_bytes()).await?)
     }
 }
 
 impl Encode for Headers {
-    async fn write(&self, w: &mut TcpStream) -> Result<(), ServerError> {
+    async fn write<W>(&self, w: &mut W) -> Result<(), ServerError>
+    where
+        W: AsyncWrite + Unpin,
+    {
         for (h, v) in &self.0 {
             w.write_all(format!("{}: {}\r\n", h, v).as_bytes()).await?;
         }
@@ -1,3 +1,4 @@
+use crate::StatusCode;
 use std::{fmt, io, num::ParseIntError, str::Utf8Error};
 use thiserror::Error;
 
@@ -20,6 +21,17 @@ pub enum HTTPParsingError {
     #[error("bad method")]
     BadMethod,
 
+    #[error("request header fields too large")]
+    HeadersTooLarge,
+    #[error("request body too large")]
+    BodyTooLarge,
+    #[error("unexpected end of stream")]
+    UnexpectedEof,
+    #[error("unsupported expectation")]
+    ExpectationFailed,
+    #[error("unsupported content coding")]
+    UnsupportedContentEncoding,
+
     #[error("parser error")]
     Parser,
 
@@ -52,4 +64,70 @@ pub enum ServerError {
 
     #[error("Parsing error")]
     Parsing(#[from] HTTPParsingError),
+
+    #[error("timed out")]
+    Timeout,
+
+    #[error("server overloaded")]
+    Overloaded,
+
+    #[error("no acceptable representation")]
+    NotAcceptable,
+
+    #[error("precondition failed")]
+    PreconditionFailed,
+}
+
+impl HTTPParsingError {
+    /// The status code a server should answer with when parsing fails this way.
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Self::BadRequestLine
+            | Self::RequestLineNotFound
+            | Self::BadFieldLine
+            | Self::BadToken
+            | Self::BadBody
+            | Self::UnexpectedEof
+            | Self::Parser
+            | Self::IntError(_)
+            | Self::UtfError(_) => StatusCode::BAD_REQUEST,
+            Self::HeadersTooLarge => StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE,
+            Self::BodyTooLarge => StatusCode::CONTENT_TOO_LARGE,
+            Self::ExpectationFailed => StatusCode::EXPECTATION_FAILED,
+            Self::UnsupportedContentEncoding => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Self::BadMethod => StatusCode::NOT_IMPLEMENTED,
+            Self::UnsupportedHTTPVersion => StatusCode::HTTP_VERSION_NOT_SUPPORTED,
+            Self::BadStatusCode | Self::IOError(_) | Self::FmtError(_) => {
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+        }
+    }
+}
+
+impl ServerError {
+    pub fn status_code(&self) -> StatusCode {
+        match self {
+            Self::IOError(err) if err.kind() == io::ErrorKind::NotFound => StatusCode::NOT_FOUND,
+            Self::Internal | Self::IOError(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::BadRequest => StatusCode::BAD_REQUEST,
+            Self::ReqwestError(_) => StatusCode::BAD_GATEWAY,
+            Self::Parsing(err) => err.status_code(),
+            Self::Timeout => StatusCode::REQUEST_TIMEOUT,
+            Self::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+            Self::NotAcceptable => StatusCode::NOT_ACCEPTABLE,
+            Self::PreconditionFailed => StatusCode::PRECONDITION_FAILED,
+        }
+    }
+
+    /// A human readable description of the error, safe to send to the client.
+    pub fn detail(&self) -> String {
+        match self {
+            // What the parser objected to, not just that it did.
+            Self::Parsing(parsing) => parsing.to_string(),
+            Self::IOError(err) if err.kind() == io::ErrorKind::NotFound => "not found".to_string(),
+            // The io and reqwest errors themselves can name server paths and
+            // hosts, so those get their generic message.
+            _ => self.to_string(),
+        }
+    }
 }
@@ -0,0 +1,355 @@
+use crate::Request;
+use std::{
+    net::{IpAddr, SocketAddr},
+    str::FromStr,
+};
+use thiserror::Error;
+
+/// A block of IP addresses, such as `10.0.0.0/8` or `2001:db8::/32`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+#[derive(Error, Debug)]
+#[error("invalid CIDR block: {0:?}")]
+pub struct InvalidCidr(String);
+
+impl Cidr {
+    pub fn new(addr: IpAddr, prefix: u8) -> Result<Self, InvalidCidr> {
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        if prefix > max {
+            return Err(InvalidCidr(format!("{addr}/{prefix}")));
+        }
+
+        Ok(Self { addr, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix as u32).unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix as u32).unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl From<IpAddr> for Cidr {
+    fn from(addr: IpAddr) -> Self {
+        let prefix = if addr.is_ipv4() { 32 } else { 128 };
+        Self { addr, prefix }
+    }
+}
+
+impl FromStr for Cidr {
+    type Err = InvalidCidr;
+
+    /// Parses `addr/prefix`, or a bare address as a single-host block.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidCidr(s.to_string());
+
+        match s.split_once('/') {
+            Some((addr, prefix)) => Cidr::new(
+                addr.parse().map_err(|_| invalid())?,
+                prefix.parse().map_err(|_| invalid())?,
+            ),
+            None => s.parse::<IpAddr>().map(Cidr::from).map_err(|_| invalid()),
+        }
+    }
+}
+
+/// Who a request really came from, once trusted proxies are looked through.
+///
+/// Built from the RFC 7239 `Forwarded` header or, if absent, from
+/// `X-Forwarded-For`, `X-Forwarded-Proto` and `X-Forwarded-Host`. Those are
+/// only believed while the hop that sent them is in
+/// [`Config::trusted_proxies`](crate::Config::trusted_proxies); anyone else
+/// could have made them up.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientInfo {
+    /// The client's IP: the peer's, or the nearest one a trusted proxy
+    /// forwarded for.
+    pub ip: Option<IpAddr>,
+    /// `http` or `https`, if a trusted proxy said which.
+    pub scheme: Option<String>,
+    /// The host the client asked for: forwarded by a trusted proxy, or the
+    /// `Host` header.
+    pub host: Option<String>,
+}
+
+/// One proxy's worth of forwarding information.
+#[derive(Debug, Default)]
+struct Hop {
+    /// `None` for `unknown` or obfuscated identifiers.
+    ip: Option<IpAddr>,
+    proto: Option<String>,
+    host: Option<String>,
+}
+
+impl ClientInfo {
+    /// Works out the client from `req`'s peer address and forwarding headers.
+    pub fn extract(req: &Request, trusted: &[Cidr]) -> Self {
+        let headers = &req.head.headers;
+        let mut info = ClientInfo {
+            ip: req.remote_addr.map(|a| a.ip()),
+            scheme: None,
+            host: headers.get("host").cloned(),
+        };
+
+        let is_trusted =
+            |ip: Option<IpAddr>| ip.is_some_and(|ip| trusted.iter().any(|c| c.contains(ip)));
+        if !is_trusted(info.ip) {
+            return info;
+        }
+
+        let hops = match headers.get("forwarded") {
+            Some(forwarded) => parse_forwarded(forwarded),
+            None => parse_x_forwarded(req),
+        };
+
+        // Walk back from the nearest proxy until we reach an address we don't
+        // trust to have told the truth about the one before it.
+        for hop in hops.iter().rev() {
+            if let Some(proto) = &hop.proto {
+                info.scheme = Some(proto.to_ascii_lowercase());
+            }
+            if let Some(host) = &hop.host {
+                info.host = Some(host.clone());
+            }
+
+            match hop.ip {
+                Some(ip) => info.ip = Some(ip),
+                None => break,
+            }
+            if !is_trusted(info.ip) {
+                break;
+            }
+        }
+
+        info
+    }
+}
+
+/// Parses `Forwarded: for=192.0.2.60;proto=http, for="[2001:db8::1]:4711"`.
+fn parse_forwarded(value: &str) -> Vec<Hop> {
+    split_unquoted(value, ',')
+        .into_iter()
+        .map(|element| {
+            let mut hop = Hop::default();
+            for pair in split_unquoted(element, ';') {
+                let Some((key, value)) = pair.split_once('=') else {
+                    continue;
+                };
+                let value = unquote(value.trim());
+
+                match key.trim().to_ascii_lowercase().as_str() {
+                    "for" => hop.ip = parse_node(&value),
+                    "proto" => hop.proto = Some(value),
+                    "host" => hop.host = Some(value),
+                    _ => {}
+                }
+            }
+            hop
+        })
+        .collect()
+}
+
+/// Splits `s` at each `sep` outside a quoted string, so `by="a,b"` stays
+/// in one piece.
+fn split_unquoted(s: &str, sep: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let (mut start, mut quoted, mut escaped) = (0, false, false);
+
+    for (i, c) in s.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if quoted => escaped = true,
+            '"' => quoted = !quoted,
+            c if c == sep && !quoted => {
+                parts.push(&s[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    parts.push(&s[start..]);
+
+    parts
+}
+
+/// The contents of a quoted string, with `\` escapes undone. Tokens come
+/// back as they are.
+fn unquote(value: &str) -> String {
+    let Some(inner) = value.strip_prefix('"').and_then(|v| v.strip_suffix('"')) else {
+        return value.to_string();
+    };
+
+    let mut out = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => out.extend(chars.next()),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Turns `X-Forwarded-For: client, proxy1` into hops. Proto and host only
+/// come from the nearest proxy, so they go on the last hop.
+fn parse_x_forwarded(req: &Request) -> Vec<Hop> {
+    let headers = &req.head.headers;
+    let Some(xff) = headers.get("x-forwarded-for") else {
+        return Vec::new();
+    };
+
+    let mut hops: Vec<Hop> = xff
+        .split(',')
+        .map(|node| Hop {
+            ip: parse_node(node.trim()),
+            ..Hop::default()
+        })
+        .collect();
+
+    let last = |name| {
+        headers
+            .get(name)
+            .and_then(|v| v.rsplit(',').next())
+            .map(|v| v.trim().to_string())
+    };
+    if let Some(hop) = hops.last_mut() {
+        hop.proto = last("x-forwarded-proto");
+        hop.host = last("x-forwarded-host");
+    }
+
+    hops
+}
+
+/// Parses a node: `192.0.2.60`, `192.0.2.60:80`, `[2001:db8::1]:4711` or
+/// `2001:db8::1`. `unknown` and obfuscated names give `None`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    node.parse::<IpAddr>()
+        .or_else(|_| node.parse::<SocketAddr>().map(|a| a.ip()))
+        .or_else(|_| {
+            node.strip_prefix('[')
+                .and_then(|n| n.strip_suffix(']'))
+                .unwrap_or(node)
+                .parse()
+        })
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(peer: &str, headers: &[(&str, &str)]) -> Request {
+        let mut req = Request::new();
+        req.remote_addr = Some(SocketAddr::new(peer.parse().unwrap(), 1234));
+        req.head
+            .headers
+            .replace("host", "internal:8080".to_string())
+            .unwrap();
+        for (k, v) in headers {
+            req.head.headers.replace(k, v.to_string()).unwrap();
+        }
+        req
+    }
+
+    fn trusted() -> Vec<Cidr> {
+        vec!["10.0.0.0/8".parse().unwrap(), "fd00::/8".parse().unwrap()]
+    }
+
+    #[test]
+    fn cidr_contains() {
+        let net: Cidr = "192.168.0.0/16".parse().unwrap();
+        assert!(net.contains("192.168.3.4".parse().unwrap()));
+        assert!(net.contains("::ffff:192.168.3.4".parse().unwrap()));
+        assert!(!net.contains("192.169.0.1".parse().unwrap()));
+
+        let any: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(any.contains("1.2.3.4".parse().unwrap()));
+
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("nonsense".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn ignores_headers_from_untrusted_peers() {
+        let req = request("203.0.113.9", &[("x-forwarded-for", "1.2.3.4")]);
+        let info = ClientInfo::extract(&req, &trusted());
+
+        assert_eq!(Some("203.0.113.9".parse().unwrap()), info.ip);
+        assert_eq!(Some("internal:8080"), info.host.as_deref());
+        assert_eq!(None, info.scheme);
+    }
+
+    #[test]
+    fn uses_forwarded_from_trusted_peers() {
+        let req = request(
+            "10.0.0.2",
+            &[(
+                "forwarded",
+                r#"for=198.51.100.17;proto=http, for="[2001:db8::1]:4711";proto=HTTPS;host=example.com, for=10.0.0.1"#,
+            )],
+        );
+        let info = ClientInfo::extract(&req, &trusted());
+
+        assert_eq!(Some("2001:db8::1".parse().unwrap()), info.ip);
+        assert_eq!(Some("https"), info.scheme.as_deref());
+        assert_eq!(Some("example.com"), info.host.as_deref());
+    }
+
+    #[test]
+    fn keeps_quoted_separators_in_forwarded() {
+        let req = request(
+            "10.0.0.2",
+            &[(
+                "forwarded",
+                r#"for=198.51.100.17, for="[2001:db8::1]:4711";by="a,b;c";host="ex\"ample.com", for=10.0.0.1"#,
+            )],
+        );
+        let info = ClientInfo::extract(&req, &trusted());
+
+        assert_eq!(Some("2001:db8::1".parse().unwrap()), info.ip);
+        assert_eq!(Some("ex\"ample.com"), info.host.as_deref());
+
+        assert_eq!(
+            vec!["for=1", r#" by="a,b""#, " for=2"],
+            split_unquoted(r#"for=1, by="a,b", for=2"#, ',')
+        );
+    }
+
+    #[test]
+    fn uses_x_forwarded_from_trusted_peers() {
+        let req = request(
+            "fd00::5",
+            &[
+                ("x-forwarded-for", "spoofed, 198.51.100.17, 10.1.1.1"),
+                ("x-forwarded-proto", "https"),
+                ("x-forwarded-host", "example.com"),
+            ],
+        );
+        let info = ClientInfo::extract(&req, &trusted());
+
+        assert_eq!(Some("198.51.100.17".parse().unwrap()), info.ip);
+        assert_eq!(Some("https"), info.scheme.as_deref());
+        assert_eq!(Some("example.com"), info.host.as_deref());
+    }
+
+    #[test]
+    fn stops_at_unknown_nodes() {
+        let req = request("10.0.0.2", &[("forwarded", "for=unknown;proto=https")]);
+        let info = ClientInfo::extract(&req, &trusted());
+
+        assert_eq!(Some("10.0.0.2".parse().unwrap()), info.ip);
+        assert_eq!(Some("https"), info.scheme.as_deref());
+    }
+}
@@ -0,0 +1,279 @@
+use super::{ServeDir, percent_decode};
+use crate::{
+    Html, IntoResponse, Request, Response, ServerError, fmt_http_date, response::escape_json,
+};
+use std::{cmp::Ordering, fmt::Write, path::Path, time::SystemTime};
+use tokio::fs;
+
+/// One file or directory in a listing.
+#[derive(Debug)]
+struct Entry {
+    name: String,
+    dir: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum SortKey {
+    Name,
+    Size,
+    Modified,
+}
+
+impl SortKey {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Name => "name",
+            Self::Size => "size",
+            Self::Modified => "modified",
+        }
+    }
+}
+
+impl ServeDir {
+    /// Lists the directory at `relative`, which `path` named.
+    pub(super) async fn list(
+        &self,
+        req: &Request,
+        relative: &Path,
+        path: &str,
+        query: Option<&str>,
+    ) -> Result<Response, ServerError> {
+        let mut entries = Vec::new();
+        let mut dir = fs::read_dir(self.root.join(relative)).await?;
+
+        while let Some(entry) = dir.next_entry().await? {
+            // Names that aren't UTF-8 can't be asked for, so aren't worth showing.
+            let Ok(name) = entry.file_name().into_string() else {
+                continue;
+            };
+            if self.hide_dotfiles && name.starts_with('.') {
+                continue;
+            }
+            if self.check_symlinks(&relative.join(&name)).await.is_err() {
+                continue;
+            }
+            // Follows links; dangling ones are skipped.
+            let Ok(meta) = fs::metadata(entry.path()).await else {
+                continue;
+            };
+
+            entries.push(Entry {
+                name,
+                dir: meta.is_dir(),
+                size: meta.len(),
+                modified: meta.modified().ok(),
+            });
+        }
+
+        let key = match param(query, "sort").as_deref() {
+            Some("size") => SortKey::Size,
+            Some("modified") => SortKey::Modified,
+            _ => SortKey::Name,
+        };
+        let desc = param(query, "order").as_deref() == Some("desc");
+        sort(&mut entries, key, desc);
+
+        let format = param(query, "format");
+        let json = match &format {
+            Some(format) => format == "json",
+            None => req.negotiate(&["text/html", "application/json"])? == "application/json",
+        };
+
+        let mut res = match json {
+            true => Response::new(Some(render_json(&entries))).content_type("application/json")?,
+            false => {
+                let title = percent_decode(path).unwrap_or_else(|| path.to_string());
+                Html(render_html(&title, &entries, key, desc)).into_response()
+            }
+        };
+        // Unless the query picked the format, caches must key on `Accept`.
+        if format.is_none() {
+            res.head.headers.add_vary("accept");
+        }
+
+        Ok(res)
+    }
+}
+
+/// Directories first, then by `key`, then by name.
+fn sort(entries: &mut [Entry], key: SortKey, desc: bool) {
+    entries.sort_by(|a, b| {
+        let order = match key {
+            SortKey::Name => Ordering::Equal,
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Modified => a.modified.cmp(&b.modified),
+        }
+        .then_with(|| a.name.cmp(&b.name));
+
+        let order = if desc { order.reverse() } else { order };
+        b.dir.cmp(&a.dir).then(order)
+    });
+}
+
+fn render_html(title: &str, entries: &[Entry], key: SortKey, desc: bool) -> String {
+    let title = escape_html(title);
+    let mut out = format!(
+        "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>Index of {title}</title></head>\n\
+         <body>\n<h1>Index of {title}</h1>\n<table>\n<thead><tr>"
+    );
+
+    // Each column sorts ascending, or flips the order if it's already the key.
+    for (column, label) in [
+        (SortKey::Name, "Name"),
+        (SortKey::Size, "Size"),
+        (SortKey::Modified, "Last modified"),
+    ] {
+        let order = if column == key && !desc {
+            "desc"
+        } else {
+            "asc"
+        };
+        let _ = write!(
+            out,
+            "<th><a href=\"?sort={}&amp;order={order}\">{label}</a></th>",
+            column.as_str()
+        );
+    }
+    out.push_str("</tr></thead>\n<tbody>\n");
+
+    if title != "/" {
+        out.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+
+    for entry in entries {
+        let slash = if entry.dir { "/" } else { "" };
+        let size = match entry.dir {
+            true => "-".to_string(),
+            false => entry.size.to_string(),
+        };
+        let modified = entry.modified.map(fmt_http_date).unwrap_or_default();
+
+        let _ = writeln!(
+            out,
+            "<tr><td><a href=\"{}{slash}\">{}{slash}</a></td><td>{size}</td><td>{modified}</td></tr>",
+            percent_encode(&entry.name),
+            escape_html(&entry.name),
+        );
+    }
+
+    out.push_str("</tbody>\n</table>\n</body>\n</html>\n");
+    out
+}
+
+fn render_json(entries: &[Entry]) -> String {
+    let entries: Vec<String> = entries
+        .iter()
+        .map(|entry| {
+            let modified = match entry.modified {
+                Some(time) => format!("\"{}\"", fmt_http_date(time)),
+                None => "null".to_string(),
+            };
+            format!(
+                r#"{{"name":"{}","type":"{}","size":{},"modified":{modified}}}"#,
+                escape_json(&entry.name),
+                if entry.dir { "directory" } else { "file" },
+                entry.size,
+            )
+        })
+        .collect();
+
+    format!("[{}]", entries.join(","))
+}
+
+/// The decoded value of `key` in a query string.
+fn param(query: Option<&str>, key: &str) -> Option<String> {
+    query?
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(k, _)| *k == key)
+        .and_then(|(_, v)| percent_decode(v))
+}
+
+fn escape_html(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&#39;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Encodes everything but unreserved characters, so a name is safe both as
+/// a path segment and inside an attribute.
+fn percent_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(b as char)
+            }
+            b => {
+                let _ = write!(out, "%{b:02X}");
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(name: &str, dir: bool, size: u64) -> Entry {
+        Entry {
+            name: name.to_string(),
+            dir,
+            size,
+            modified: None,
+        }
+    }
+
+    fn names(entries: &[Entry]) -> Vec<&str> {
+        entries.iter().map(|e| e.name.as_str()).collect()
+    }
+
+    #[test]
+    fn sorts_directories_first() {
+        let mut entries = vec![
+            entry("b.txt", false, 1),
+            entry("z", true, 0),
+            entry("a.txt", false, 3),
+            entry("c", true, 0),
+        ];
+
+        sort(&mut entries, SortKey::Name, false);
+        assert_eq!(vec!["c", "z", "a.txt", "b.txt"], names(&entries));
+
+        sort(&mut entries, SortKey::Size, true);
+        assert_eq!(vec!["z", "c", "a.txt", "b.txt"], names(&entries));
+    }
+
+    #[test]
+    fn escapes_names() {
+        let html = render_html(
+            "/<b>/",
+            &[entry("<script>&\"x\".txt", false, 1)],
+            SortKey::Name,
+            false,
+        );
+
+        assert!(html.contains("<title>Index of /&lt;b&gt;/</title>"));
+        assert!(html.contains(
+            "<a href=\"%3Cscript%3E%26%22x%22.txt\">&lt;script&gt;&amp;&quot;x&quot;.txt</a>"
+        ));
+        assert!(!html.contains("<script>"));
+
+        assert_eq!(
+            r#"[{"name":"a\"b","type":"file","size":1,"modified":null}]"#,
+            render_json(&[entry("a\"b", false, 1)])
+        );
+    }
+}
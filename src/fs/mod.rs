@@ -0,0 +1,537 @@
+use crate::{
+    Body, ETag, Headers, IntoResponse, Request, Response, ServerError, StatusCode, apply_range,
+};
+use std::{
+    ffi::OsStr,
+    io,
+    path::{Path, PathBuf},
+};
+use tokio::fs;
+
+mod listing;
+
+/// What [`ServeDir`] does with symbolic links under its root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SymlinkPolicy {
+    /// Any link on the way to a file makes it a 404.
+    Deny,
+    /// Links are followed as long as they end up inside the root.
+    #[default]
+    WithinRoot,
+    /// Links are followed wherever they point.
+    Follow,
+}
+
+/// Serves the files under a directory, mapping the request path onto it.
+#[derive(Debug, Clone)]
+pub struct ServeDir {
+    root: PathBuf,
+    index: Option<String>,
+    symlinks: SymlinkPolicy,
+    hide_dotfiles: bool,
+    listing: bool,
+}
+
+impl ServeDir {
+    pub fn new(root: impl Into<PathBuf>) -> Self {
+        Self {
+            root: root.into(),
+            index: Some("index.html".to_string()),
+            symlinks: SymlinkPolicy::default(),
+            hide_dotfiles: true,
+            listing: false,
+        }
+    }
+
+    /// The file served for a directory. Defaults to `index.html`.
+    pub fn index_file(mut self, name: Option<&str>) -> Self {
+        self.index = name.map(str::to_string);
+        self
+    }
+
+    pub fn symlinks(mut self, policy: SymlinkPolicy) -> Self {
+        self.symlinks = policy;
+        self
+    }
+
+    /// Whether names starting with `.`, like `.env` or `.git/config`, are
+    /// kept out of reach. On by default: any such segment in the path is a
+    /// 404, and listings leave them out.
+    pub fn hide_dotfiles(mut self, hide: bool) -> Self {
+        self.hide_dotfiles = hide;
+        self
+    }
+
+    /// Lists directories that have no index file instead of answering 404.
+    /// The listing is HTML, or JSON for `?format=json` and clients that ask
+    /// for it, and can be sorted with `?sort=name|size|modified&order=desc`.
+    pub fn listing(mut self, enabled: bool) -> Self {
+        self.listing = enabled;
+        self
+    }
+
+    /// Serves the file `req`'s path names, relative to the root.
+    pub async fn serve(&self, req: &Request) -> Result<Response, ServerError> {
+        if let Some(res) = only_get(req) {
+            return Ok(res);
+        }
+
+        let (path, query) = split_uri(&req.head.uri);
+        let Some(relative) = sanitize(path) else {
+            return Err(not_found());
+        };
+        if self.hide_dotfiles && relative.components().any(|c| is_hidden(c.as_os_str())) {
+            return Err(not_found());
+        }
+        let full = self.root.join(&relative);
+
+        self.check_symlinks(&relative).await?;
+
+        if !fs::metadata(&full).await?.is_dir() {
+            return ServeFile::new(full).serve(req).await;
+        }
+
+        // Relative links in the page only resolve against a trailing slash.
+        if !path.ends_with('/') {
+            let location = match query {
+                Some(q) => format!("{path}/?{q}"),
+                None => format!("{path}/"),
+            };
+            return redirect(location);
+        }
+
+        if let Some(index) = &self.index {
+            self.check_symlinks(&relative.join(index)).await?;
+            match ServeFile::new(full.join(index)).serve(req).await {
+                Err(ServerError::IOError(err))
+                    if self.listing && err.kind() == io::ErrorKind::NotFound => {}
+                res => return res,
+            }
+        }
+
+        match self.listing {
+            true => self.list(req, &relative, path, query).await,
+            false => Err(not_found()),
+        }
+    }
+
+    /// Applies the symlink policy to every component of `relative`.
+    async fn check_symlinks(&self, relative: &Path) -> Result<(), ServerError> {
+        if self.symlinks == SymlinkPolicy::Follow {
+            return Ok(());
+        }
+
+        let mut path = self.root.clone();
+        for part in relative.components() {
+            path.push(part);
+
+            let Ok(meta) = fs::symlink_metadata(&path).await else {
+                // Missing files are reported by whoever opens them.
+                return Ok(());
+            };
+            if !meta.is_symlink() {
+                continue;
+            }
+
+            if self.symlinks == SymlinkPolicy::Deny {
+                return Err(not_found());
+            }
+
+            let root = fs::canonicalize(&self.root).await?;
+            if !fs::canonicalize(&path).await?.starts_with(&root) {
+                return Err(not_found());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Serves one file, whatever the request path.
+#[derive(Debug, Clone)]
+pub struct ServeFile {
+    path: PathBuf,
+    content_type: Option<String>,
+}
+
+impl ServeFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            content_type: None,
+        }
+    }
+
+    /// Overrides the type guessed from the file's extension.
+    pub fn content_type(mut self, content_type: impl Into<String>) -> Self {
+        self.content_type = Some(content_type.into());
+        self
+    }
+
+    pub async fn serve(&self, req: &Request) -> Result<Response, ServerError> {
+        if let Some(res) = only_get(req) {
+            return Ok(res);
+        }
+
+        let file = fs::File::open(&self.path).await?;
+        let meta = file.metadata().await?;
+        if !meta.is_file() {
+            return Err(not_found());
+        }
+
+        let content_type = match &self.content_type {
+            Some(t) => t.as_str(),
+            None => mime_type(&self.path),
+        };
+
+        let etag = ETag::from_metadata(&meta);
+        let body = Body::file(file.into_std().await, 0, meta.len());
+        let mut res = Response::new(Some(body))
+            .content_type(content_type)?
+            .etag(&etag);
+        if let Ok(modified) = meta.modified() {
+            res = res.last_modified(modified);
+        }
+
+        // Conditional headers are left to the connection, which checks them
+        // against the response as sent, compressed or not.
+        Ok(apply_range(req, res))
+    }
+}
+
+/// A `405` for anything but `GET` and `HEAD`.
+fn only_get(req: &Request) -> Option<Response> {
+    if req.head.method == "GET" || req.head.method == "HEAD" {
+        return None;
+    }
+
+    let mut headers = Headers::new();
+    headers.replace("allow", "GET, HEAD".to_string()).ok()?;
+
+    Some((StatusCode::METHOD_NOT_ALLOWED, headers, ()).into_response())
+}
+
+fn redirect(location: String) -> Result<Response, ServerError> {
+    let mut headers = Headers::new();
+    headers.replace("location", location)?;
+
+    Ok((StatusCode::PERMANENT_REDIRECT, headers, ()).into_response())
+}
+
+fn not_found() -> ServerError {
+    io::Error::from(io::ErrorKind::NotFound).into()
+}
+
+fn split_uri(uri: &str) -> (&str, Option<&str>) {
+    let uri = uri.split('#').next().unwrap_or_default();
+
+    match uri.split_once('?') {
+        Some((path, query)) => (path, Some(query)),
+        None => (uri, None),
+    }
+}
+
+/// Turns a URL path into a relative filesystem path, or `None` if it tries
+/// to leave the root or is otherwise unsafe.
+fn sanitize(path: &str) -> Option<PathBuf> {
+    let mut out = PathBuf::new();
+
+    for segment in path.split('/') {
+        let segment = percent_decode(segment)?;
+
+        match segment.as_str() {
+            "" | "." => continue,
+            ".." => return None,
+            s if s.contains(['/', '\\', '\0']) => return None,
+            // Drive letters and the like on Windows.
+            s if cfg!(windows) && s.contains(':') => return None,
+            s => out.push(s),
+        }
+    }
+
+    Some(out)
+}
+
+/// Whether `name` is a dotfile.
+fn is_hidden(name: &OsStr) -> bool {
+    name.as_encoded_bytes().starts_with(b".")
+}
+
+fn percent_decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+
+    while let Some(b) = iter.next() {
+        if b != b'%' {
+            bytes.push(b);
+            continue;
+        }
+
+        let hex = [iter.next()?, iter.next()?];
+        let hex = std::str::from_utf8(&hex).ok()?;
+        bytes.push(u8::from_str_radix(hex, 16).ok()?);
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+/// Guesses a `Content-Type` from `path`'s extension.
+pub fn mime_type(path: &Path) -> &'static str {
+    let ext = path
+        .extension()
+        .and_then(|e| e.to_str())
+        .map(str::to_ascii_lowercase)
+        .unwrap_or_default();
+
+    match ext.as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "md" => "text/markdown; charset=utf-8",
+        "csv" => "text/csv; charset=utf-8",
+        "xml" => "application/xml",
+        "wasm" => "application/wasm",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" => "application/gzip",
+        "tar" => "application/x-tar",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "bmp" => "image/bmp",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "ttf" => "font/ttf",
+        "otf" => "font/otf",
+        "mp4" => "video/mp4",
+        "webm" => "video/webm",
+        "ogg" => "audio/ogg",
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        "m4a" => "audio/mp4",
+        _ => "application/octet-stream",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Encode;
+
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("httpfromtcp-{}-{test}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn get(uri: &str) -> Request {
+        let mut req = Request::new();
+        req.head.uri = uri.to_string();
+        req
+    }
+
+    async fn body(res: Response) -> String {
+        let mut out = Vec::new();
+        res.body.write(&mut out).await.unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn sanitizes_paths() {
+        assert_eq!(Some(PathBuf::from("a/b.txt")), sanitize("/a/./b.txt"));
+        assert_eq!(Some(PathBuf::from("a b")), sanitize("/a%20b"));
+        assert_eq!(None, sanitize("/a/../../etc/passwd"));
+        assert_eq!(None, sanitize("/%2e%2e/etc/passwd"));
+        assert_eq!(None, sanitize("/a%2f..%2fb"));
+        assert_eq!(None, sanitize("/a%00"));
+        assert_eq!(None, sanitize("/a%zz"));
+    }
+
+    #[test]
+    fn guesses_mime_types() {
+        assert_eq!("video/mp4", mime_type(Path::new("a/vim.MP4")));
+        assert_eq!(
+            "text/html; charset=utf-8",
+            mime_type(Path::new("index.html"))
+        );
+        assert_eq!("application/octet-stream", mime_type(Path::new("Makefile")));
+    }
+
+    #[tokio::test]
+    async fn serves_files_and_index() {
+        let dir = temp_dir("servedir");
+        std::fs::create_dir_all(dir.join("docs")).unwrap();
+        std::fs::write(dir.join("style.css"), "body {}").unwrap();
+        std::fs::write(dir.join("docs/index.html"), "<h1>docs</h1>").unwrap();
+        let serve = ServeDir::new(&dir);
+
+        let res = serve.serve(&get("/style.css?v=1")).await.unwrap();
+        assert_eq!(
+            "text/css; charset=utf-8",
+            res.head.headers.get("content-type").unwrap()
+        );
+        assert_eq!("7", res.head.headers.get("content-length").unwrap());
+        assert_eq!("body {}", body(res).await);
+
+        let res = serve.serve(&get("/docs/")).await.unwrap();
+        assert_eq!("<h1>docs</h1>", body(res).await);
+
+        let res = serve.serve(&get("/docs?x=1")).await.unwrap();
+        assert_eq!(StatusCode::PERMANENT_REDIRECT, res.head.status);
+        assert_eq!("/docs/?x=1", res.head.headers.get("location").unwrap());
+
+        let err = serve.serve(&get("/../secret")).await.unwrap_err();
+        assert_eq!(StatusCode::NOT_FOUND, err.status_code());
+
+        let err = serve.serve(&get("/missing")).await.unwrap_err();
+        assert_eq!(StatusCode::NOT_FOUND, err.status_code());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn hides_dotfiles() {
+        let dir = temp_dir("dotfiles");
+        std::fs::create_dir_all(dir.join(".git")).unwrap();
+        std::fs::write(dir.join(".git/config"), "[core]").unwrap();
+        std::fs::write(dir.join(".env"), "SECRET=1").unwrap();
+        std::fs::write(dir.join("app.js"), "").unwrap();
+        let serve = ServeDir::new(&dir).listing(true);
+
+        for uri in ["/.env", "/%2eenv", "/.git/config", "/.git/"] {
+            let err = serve.serve(&get(uri)).await.unwrap_err();
+            assert_eq!(StatusCode::NOT_FOUND, err.status_code(), "{uri}");
+        }
+
+        let html = body(serve.serve(&get("/")).await.unwrap()).await;
+        assert!(html.contains("app.js"));
+        assert!(!html.contains(".env") && !html.contains(".git"));
+
+        let res = serve
+            .hide_dotfiles(false)
+            .serve(&get("/.env"))
+            .await
+            .unwrap();
+        assert_eq!("SECRET=1", body(res).await);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn lists_directories_without_index() {
+        let dir = temp_dir("listing");
+        std::fs::create_dir_all(dir.join("builds/nightly")).unwrap();
+        std::fs::write(dir.join("builds/app.tar"), "tar").unwrap();
+
+        let err = ServeDir::new(&dir)
+            .serve(&get("/builds/"))
+            .await
+            .unwrap_err();
+        assert_eq!(StatusCode::NOT_FOUND, err.status_code());
+
+        let serve = ServeDir::new(&dir).listing(true);
+        let res = serve.serve(&get("/builds/")).await.unwrap();
+        assert_eq!(
+            "text/html; charset=utf-8",
+            res.head.headers.get("content-type").unwrap()
+        );
+        assert_eq!("accept", res.head.headers.get("vary").unwrap());
+        let html = body(res).await;
+        assert!(html.contains("<a href=\"nightly/\">nightly/</a>"));
+        assert!(html.contains("<a href=\"app.tar\">app.tar</a></td><td>3</td>"));
+
+        let res = serve
+            .serve(&get("/builds/?format=json&sort=size"))
+            .await
+            .unwrap();
+        assert!(res.head.headers.get("vary").is_none());
+        let json = body(res).await;
+        assert!(json.starts_with(r#"[{"name":"nightly","type":"directory""#));
+        assert!(json.contains(r#"{"name":"app.tar","type":"file","size":3,"modified":""#));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn serves_ranges_of_files() {
+        let dir = temp_dir("ranges");
+        std::fs::write(dir.join("digits.txt"), "0123456789").unwrap();
+
+        let mut req = get("/digits.txt");
+        req.head
+            .headers
+            .replace("range", "bytes=-4".to_string())
+            .unwrap();
+        let res = ServeDir::new(&dir).serve(&req).await.unwrap();
+
+        assert_eq!(StatusCode::PARTIAL_CONTENT, res.head.status);
+        assert_eq!(
+            "bytes 6-9/10",
+            res.head.headers.get("content-range").unwrap()
+        );
+        assert_eq!("6789", body(res).await);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn revalidates_files() {
+        let dir = temp_dir("conditional");
+        std::fs::write(dir.join("a.txt"), "a").unwrap();
+
+        let res = ServeDir::new(&dir).serve(&get("/a.txt")).await.unwrap();
+        let etag = res.head.headers.get("etag").unwrap().clone();
+        let modified = res.head.headers.get("last-modified").unwrap().clone();
+
+        let mut req = get("/a.txt");
+        req.head.headers.replace("if-none-match", etag).unwrap();
+        let res = ServeDir::new(&dir).serve(&req).await.unwrap();
+        let res = crate::preconditions(&req, res);
+        assert_eq!(StatusCode::NOT_MODIFIED, res.head.status);
+        assert!(res.body.is_empty());
+
+        // A matching date still answers ranges with the part asked for.
+        let mut req = get("/a.txt");
+        req.head.headers.replace("if-range", modified).unwrap();
+        req.head
+            .headers
+            .replace("range", "bytes=0-0".to_string())
+            .unwrap();
+        let res = ServeDir::new(&dir).serve(&req).await.unwrap();
+        assert_eq!(StatusCode::PARTIAL_CONTENT, res.head.status);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn applies_symlink_policy() {
+        let dir = temp_dir("symlinks");
+        let outside = temp_dir("symlinks-outside");
+        std::fs::create_dir_all(dir.join("root")).unwrap();
+        std::fs::write(dir.join("root/real.txt"), "real").unwrap();
+        std::fs::write(outside.join("secret.txt"), "secret").unwrap();
+        std::os::unix::fs::symlink(dir.join("root/real.txt"), dir.join("root/inside")).unwrap();
+        std::os::unix::fs::symlink(outside.join("secret.txt"), dir.join("root/outside")).unwrap();
+
+        let within = ServeDir::new(dir.join("root"));
+        assert!(within.serve(&get("/inside")).await.is_ok());
+        assert!(within.serve(&get("/outside")).await.is_err());
+
+        let deny = within.clone().symlinks(SymlinkPolicy::Deny);
+        assert!(deny.serve(&get("/inside")).await.is_err());
+        assert!(deny.serve(&get("/real.txt")).await.is_ok());
+
+        let follow = within.symlinks(SymlinkPolicy::Follow);
+        assert!(follow.serve(&get("/outside")).await.is_ok());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+        std::fs::remove_dir_all(&outside).unwrap();
+    }
+}
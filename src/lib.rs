@@ -1,20 +1,42 @@
+mod compression;
+mod conditional;
+mod config;
 mod connection;
+mod cors;
 mod encoder;
 mod error;
+mod forwarded;
+mod fs;
 mod listener;
+mod negotiate;
 mod parts;
+mod proxy;
+mod range;
 mod request;
 mod response;
 mod server;
+mod tcp;
+mod tls;
 
+pub use compression::*;
+pub use conditional::*;
+pub use config::*;
 pub use connection::*;
+pub use cors::*;
 pub use encoder::Encode;
 pub use error::*;
+pub use forwarded::*;
+pub use fs::*;
 pub use listener::*;
+pub use negotiate::*;
 pub const SERVER_PORT: u16 = 42069;
 pub use parts::*;
+pub use proxy::*;
+pub use range::*;
 pub use request::Request;
-pub use response::{IntoResponse, Response};
+pub use response::{Body, Html, Informational, IntoResponse, Response};
 pub use server::*;
+pub use tcp::*;
+pub use tls::*;
 
 const SEPARATOR: &[u8] = b"\r\n";
@@ -1,40 +1,389 @@
-use std::{net::SocketAddr, time::Duration};
+use crate::TcpOptions;
+use std::{
+    fmt,
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+#[cfg(unix)]
+use std::{fs, path::Path};
 
+#[cfg(unix)]
+use tokio::net::{
+    UnixListener, UnixStream,
+    unix::{self, UCred},
+};
 use tokio::{
-    io::{self, AsyncRead, AsyncWrite},
+    io::{self, AsyncRead, AsyncWrite, ReadBuf},
     net::{TcpListener, TcpStream},
 };
 
 pub trait Listener: Send + 'static {
     type Io: AsyncRead + AsyncWrite + Unpin + Send + 'static;
 
-    type Addr: Send;
+    type Addr: RemoteAddr;
+
+    /// Waits for the next connection. Errors are handed to the server's
+    /// [`AcceptErrorPolicy`], which decides whether to retry.
+    fn accept(&mut self) -> impl Future<Output = io::Result<(Self::Io, Self::Addr)>> + Send;
+
+    /// Accepts from both `self` and `other`, so one server can serve e.g. an
+    /// IPv4, an IPv6 and a Unix socket at once:
+    /// `v4.or(v6).or(unix)`.
+    fn or<B>(self, other: B) -> Or<Self, B>
+    where
+        Self: Sized,
+        B: Listener,
+    {
+        Or { a: self, b: other }
+    }
+}
+
+/// The address a connection was accepted from.
+pub trait RemoteAddr: Send + fmt::Debug + 'static {
+    /// The client's IP address and port, if it has one. Handlers see it as
+    /// [`Request::remote_addr`](crate::Request::remote_addr).
+    fn socket_addr(&self) -> Option<SocketAddr>;
+}
+
+impl RemoteAddr for SocketAddr {
+    fn socket_addr(&self) -> Option<SocketAddr> {
+        Some(*self)
+    }
+}
+
+impl RemoteAddr for () {
+    fn socket_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+impl<A: RemoteAddr, B: RemoteAddr> RemoteAddr for Either<A, B> {
+    fn socket_addr(&self) -> Option<SocketAddr> {
+        match self {
+            Either::Left(a) => a.socket_addr(),
+            Either::Right(b) => b.socket_addr(),
+        }
+    }
+}
+
+/// A [`Listener`] accepting from two others. Built with [`Listener::or`].
+pub struct Or<A, B> {
+    a: A,
+    b: B,
+}
+
+impl<A, B> Listener for Or<A, B>
+where
+    A: Listener,
+    B: Listener,
+{
+    type Io = Either<A::Io, B::Io>;
+    type Addr = Either<A::Addr, B::Addr>;
+
+    async fn accept(&mut self) -> io::Result<(Self::Io, Self::Addr)> {
+        tokio::select! {
+            conn = self.a.accept() => conn.map(|(io, addr)| (Either::Left(io), Either::Left(addr))),
+            conn = self.b.accept() => conn.map(|(io, addr)| (Either::Right(io), Either::Right(addr))),
+        }
+    }
+}
+
+/// A stream or address from one of the two sides of an [`Or`].
+pub enum Either<A, B> {
+    Left(A),
+    Right(B),
+}
+
+impl<A: fmt::Debug, B: fmt::Debug> fmt::Debug for Either<A, B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Either::Left(a) => a.fmt(f),
+            Either::Right(b) => b.fmt(f),
+        }
+    }
+}
+
+impl<A, B> AsyncRead for Either<A, B>
+where
+    A: AsyncRead + Unpin,
+    B: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Either::Left(a) => Pin::new(a).poll_read(cx, buf),
+            Either::Right(b) => Pin::new(b).poll_read(cx, buf),
+        }
+    }
+}
+
+impl<A, B> AsyncWrite for Either<A, B>
+where
+    A: AsyncWrite + Unpin,
+    B: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Either::Left(a) => Pin::new(a).poll_write(cx, buf),
+            Either::Right(b) => Pin::new(b).poll_write(cx, buf),
+        }
+    }
 
-    fn accept(&mut self) -> impl Future<Output = (Self::Io, Self::Addr)> + Send;
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Either::Left(a) => Pin::new(a).poll_flush(cx),
+            Either::Right(b) => Pin::new(b).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Either::Left(a) => Pin::new(a).poll_shutdown(cx),
+            Either::Right(b) => Pin::new(b).poll_shutdown(cx),
+        }
+    }
 }
 
 impl Listener for TcpListener {
     type Io = TcpStream;
     type Addr = SocketAddr;
 
-    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
-        loop {
-            match Self::accept(self).await {
-                Ok(tup) => return tup,
-                Err(e) => handle_error(e).await,
+    async fn accept(&mut self) -> io::Result<(Self::Io, Self::Addr)> {
+        let (stream, addr) = Self::accept(self).await?;
+
+        if let Err(err) = TcpOptions::default().apply(&stream) {
+            tracing::debug!("failed to set socket options for {addr}: {err}");
+        }
+
+        Ok((stream, addr))
+    }
+}
+
+/// Address of a peer connected over a Unix domain socket.
+#[cfg(unix)]
+#[derive(Debug, Clone)]
+pub struct UnixAddr {
+    pub addr: unix::SocketAddr,
+    /// Credentials of the peer process, if the OS reports them.
+    pub cred: Option<UCred>,
+}
+
+#[cfg(unix)]
+impl Listener for UnixListener {
+    type Io = UnixStream;
+    type Addr = UnixAddr;
+
+    async fn accept(&mut self) -> io::Result<(Self::Io, Self::Addr)> {
+        let (io, addr) = Self::accept(self).await?;
+        let cred = io.peer_cred().ok();
+
+        Ok((io, UnixAddr { addr, cred }))
+    }
+}
+
+#[cfg(unix)]
+impl RemoteAddr for UnixAddr {
+    fn socket_addr(&self) -> Option<SocketAddr> {
+        None
+    }
+}
+
+/// Binds a Unix domain socket at `path`.
+///
+/// A socket file left behind by a process that is no longer listening is
+/// removed first. A live socket or any other kind of file is left alone and
+/// reported as an error.
+#[cfg(unix)]
+pub fn bind_unix(path: impl AsRef<Path>) -> io::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    let path = path.as_ref();
+
+    match fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => {
+            match std::os::unix::net::UnixStream::connect(path) {
+                Ok(_) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AddrInUse,
+                        format!("{} is in use by another process", path.display()),
+                    ));
+                }
+                Err(e) if e.kind() == io::ErrorKind::ConnectionRefused => {
+                    tracing::info!("removing stale socket file {}", path.display());
+                    fs::remove_file(path)?;
+                }
+                Err(e) => return Err(e),
             }
         }
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ));
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+
+    UnixListener::bind(path)
+}
+
+/// How an accept error is treated by [`handle_error`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptErrorClass {
+    /// The peer went away before we got to it. Retried immediately.
+    Connection,
+    /// Out of file descriptors, buffers or memory. Retried with exponential
+    /// backoff, giving other connections time to close.
+    ResourceExhausted,
+    /// Anything else. Retried with backoff.
+    Other,
+}
+
+impl AcceptErrorClass {
+    pub fn of(e: &io::Error) -> Self {
+        if is_connection_error(e) {
+            Self::Connection
+        } else if is_resource_exhausted(e) {
+            Self::ResourceExhausted
+        } else {
+            Self::Other
+        }
+    }
+}
+
+/// What the accept loop does next, as decided by [`handle_error`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcceptAction {
+    Retry,
+    RetryAfter(Duration),
+    /// Stop accepting and shut the server down, returning the error from
+    /// [`Server::serve`](crate::Server::serve).
+    Fatal,
+}
+
+type ErrorHook = Arc<dyn Fn(&io::Error, AcceptErrorClass) + Send + Sync>;
+type FatalCheck = Arc<dyn Fn(&io::Error, AcceptErrorClass) -> bool + Send + Sync>;
+
+/// How a server reacts to errors from [`Listener::accept`].
+#[derive(Clone)]
+pub struct AcceptErrorPolicy {
+    initial_backoff: Duration,
+    max_backoff: Duration,
+    on_error: Option<ErrorHook>,
+    fatal: Option<FatalCheck>,
+}
+
+impl Default for AcceptErrorPolicy {
+    fn default() -> Self {
+        Self {
+            initial_backoff: Duration::from_millis(10),
+            max_backoff: Duration::from_secs(1),
+            on_error: None,
+            fatal: None,
+        }
+    }
+}
+
+impl fmt::Debug for AcceptErrorPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AcceptErrorPolicy")
+            .field("initial_backoff", &self.initial_backoff)
+            .field("max_backoff", &self.max_backoff)
+            .field("on_error", &self.on_error.is_some())
+            .field("fatal", &self.fatal.is_some())
+            .finish()
     }
 }
 
-pub async fn handle_error(e: io::Error) {
-    if is_connection_error(&e) {
-        return;
+impl AcceptErrorPolicy {
+    /// Backoff starts at `initial` and doubles on every consecutive error up
+    /// to `max`.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_backoff = initial;
+        self.max_backoff = max.max(initial);
+        self
     }
 
-    tracing::error!("accept error: {e}");
+    /// Called on every accept error, e.g. to count them in metrics.
+    pub fn on_error<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&io::Error, AcceptErrorClass) + Send + Sync + 'static,
+    {
+        self.on_error = Some(Arc::new(f));
+        self
+    }
 
-    tokio::time::sleep(Duration::from_secs(1)).await;
+    /// Errors for which `f` returns true stop the server, so a supervisor
+    /// can restart the process.
+    pub fn fatal_if<F>(mut self, f: F) -> Self
+    where
+        F: Fn(&io::Error, AcceptErrorClass) -> bool + Send + Sync + 'static,
+    {
+        self.fatal = Some(Arc::new(f));
+        self
+    }
+}
+
+/// Consecutive-error state of one accept loop.
+#[derive(Debug, Default)]
+pub struct Backoff {
+    current: Option<Duration>,
+}
+
+impl Backoff {
+    fn next(&mut self, policy: &AcceptErrorPolicy) -> Duration {
+        let next = match self.current {
+            Some(d) => (d * 2).min(policy.max_backoff),
+            None => policy.initial_backoff,
+        };
+        self.current = Some(next);
+        next
+    }
+
+    /// Called after a successful accept.
+    pub fn reset(&mut self) {
+        self.current = None;
+    }
+}
+
+pub fn handle_error(
+    e: &io::Error,
+    policy: &AcceptErrorPolicy,
+    backoff: &mut Backoff,
+) -> AcceptAction {
+    let class = AcceptErrorClass::of(e);
+
+    if let Some(hook) = &policy.on_error {
+        hook(e, class);
+    }
+
+    if policy.fatal.as_ref().is_some_and(|fatal| fatal(e, class)) {
+        tracing::error!(error = %e, ?class, "fatal accept error");
+        return AcceptAction::Fatal;
+    }
+
+    match class {
+        AcceptErrorClass::Connection => {
+            tracing::debug!(error = %e, ?class, "accept error, retrying");
+            AcceptAction::Retry
+        }
+        _ => {
+            let delay = backoff.next(policy);
+            tracing::error!(error = %e, ?class, ?delay, "accept error, backing off");
+            AcceptAction::RetryAfter(delay)
+        }
+    }
 }
 
 fn is_connection_error(e: &io::Error) -> bool {
@@ -43,5 +392,171 @@ fn is_connection_error(e: &io::Error) -> bool {
         io::ErrorKind::ConnectionRefused
             | io::ErrorKind::ConnectionAborted
             | io::ErrorKind::ConnectionReset
+            | io::ErrorKind::Interrupted
     )
 }
+
+fn is_resource_exhausted(e: &io::Error) -> bool {
+    if e.kind() == io::ErrorKind::OutOfMemory {
+        return true;
+    }
+
+    #[cfg(unix)]
+    if let Some(code) = e.raw_os_error() {
+        return matches!(
+            code,
+            libc::EMFILE | libc::ENFILE | libc::ENOBUFS | libc::ENOMEM
+        );
+    }
+
+    false
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::Server;
+    use std::path::PathBuf;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("httpfromtcp-{}-{name}.sock", std::process::id()))
+    }
+
+    #[tokio::test]
+    async fn bind_unix_replaces_stale_socket() {
+        let path = socket_path("stale");
+        drop(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert!(path.exists());
+
+        let listener = bind_unix(&path).unwrap();
+        assert!(bind_unix(&path).is_err());
+
+        drop(listener);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn bind_unix_leaves_regular_files_alone() {
+        let path = socket_path("regular");
+        fs::write(&path, b"not a socket").unwrap();
+
+        assert!(bind_unix(&path).is_err());
+        assert_eq!(b"not a socket".as_slice(), fs::read(&path).unwrap());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn serves_over_unix_socket() {
+        let path = socket_path("serve");
+        let listener = bind_unix(&path).unwrap();
+        tokio::spawn(
+            Server::builder()
+                .build()
+                .serve_with(listener, std::future::pending()),
+        );
+
+        let mut client = UnixStream::connect(&path).await.unwrap();
+        client
+            .write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+
+        let mut res = String::new();
+        client.read_to_string(&mut res).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn or_serves_tcp_and_unix_together() {
+        let path = socket_path("or");
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let listener = tcp.or(bind_unix(&path).unwrap());
+
+        tokio::spawn(
+            Server::builder()
+                .build()
+                .serve_with(listener, std::future::pending()),
+        );
+
+        let req = b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n";
+
+        let mut unix = UnixStream::connect(&path).await.unwrap();
+        unix.write_all(req).await.unwrap();
+        let mut res = String::new();
+        unix.read_to_string(&mut res).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let mut tcp = TcpStream::connect(addr).await.unwrap();
+        tcp.write_all(req).await.unwrap();
+        let mut res = String::new();
+        tcp.read_to_string(&mut res).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn resource_exhaustion_backs_off_exponentially() {
+        let policy = AcceptErrorPolicy::default()
+            .backoff(Duration::from_millis(10), Duration::from_millis(30));
+        let mut backoff = Backoff::default();
+        let emfile = io::Error::from_raw_os_error(libc::EMFILE);
+
+        assert_eq!(
+            AcceptErrorClass::ResourceExhausted,
+            AcceptErrorClass::of(&emfile)
+        );
+        assert_eq!(
+            AcceptAction::RetryAfter(Duration::from_millis(10)),
+            handle_error(&emfile, &policy, &mut backoff)
+        );
+        assert_eq!(
+            AcceptAction::RetryAfter(Duration::from_millis(20)),
+            handle_error(&emfile, &policy, &mut backoff)
+        );
+        assert_eq!(
+            AcceptAction::RetryAfter(Duration::from_millis(30)),
+            handle_error(&emfile, &policy, &mut backoff)
+        );
+
+        backoff.reset();
+        assert_eq!(
+            AcceptAction::RetryAfter(Duration::from_millis(10)),
+            handle_error(&emfile, &policy, &mut backoff)
+        );
+    }
+
+    #[test]
+    fn connection_errors_retry_immediately_and_hit_the_hook() {
+        let seen = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let counter = seen.clone();
+        let policy = AcceptErrorPolicy::default().on_error(move |_, class| {
+            assert_eq!(AcceptErrorClass::Connection, class);
+            counter.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        });
+
+        let reset = io::Error::from(io::ErrorKind::ConnectionReset);
+        assert_eq!(
+            AcceptAction::Retry,
+            handle_error(&reset, &policy, &mut Backoff::default())
+        );
+        assert_eq!(1, seen.load(std::sync::atomic::Ordering::Relaxed));
+    }
+
+    #[test]
+    fn fatal_predicate_stops_the_loop() {
+        let policy = AcceptErrorPolicy::default()
+            .fatal_if(|_, class| class == AcceptErrorClass::ResourceExhausted);
+
+        let enfile = io::Error::from_raw_os_error(libc::ENFILE);
+        assert_eq!(
+            AcceptAction::Fatal,
+            handle_error(&enfile, &policy, &mut Backoff::default())
+        );
+    }
+}
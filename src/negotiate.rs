@@ -0,0 +1,288 @@
+use crate::{Request, ServerError};
+
+/// One element of an `Accept`-style header: a value, its parameters and its
+/// weight, e.g. `text/html;level=1;q=0.5`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QualityItem<'a> {
+    pub value: &'a str,
+    /// Parameters before `q`, with quotes stripped. Those after it are
+    /// extensions and dropped.
+    pub params: Vec<(&'a str, &'a str)>,
+    pub q: f32,
+}
+
+/// Parses a comma-separated list of weighted values. Empty elements are
+/// skipped; an unparsable `q` counts as 0.
+pub fn parse_quality_list(header: &str) -> Vec<QualityItem<'_>> {
+    let mut items = Vec::new();
+
+    for element in header.split(',') {
+        let mut parts = element.split(';');
+        let value = parts.next().unwrap_or_default().trim();
+        if value.is_empty() {
+            continue;
+        }
+
+        let mut params = Vec::new();
+        let mut q = 1.0;
+        for param in parts {
+            let Some((k, v)) = param.split_once('=') else {
+                continue;
+            };
+            let (k, v) = (k.trim(), v.trim());
+            if k.eq_ignore_ascii_case("q") {
+                q = v.parse::<f32>().unwrap_or(0.0).clamp(0.0, 1.0);
+                break;
+            }
+            params.push((k, v.trim_matches('"')));
+        }
+
+        items.push(QualityItem { value, params, q });
+    }
+
+    items
+}
+
+/// A media range from `Accept`, e.g. `text/*` or `application/json;q=0.9`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaRange<'a> {
+    pub kind: &'a str,
+    pub subtype: &'a str,
+    pub params: Vec<(&'a str, &'a str)>,
+    pub q: f32,
+}
+
+impl<'a> MediaRange<'a> {
+    /// Parses an `Accept` header, skipping ranges that aren't `type/subtype`.
+    pub fn parse_list(accept: &'a str) -> Vec<Self> {
+        parse_quality_list(accept)
+            .into_iter()
+            .filter_map(|item| {
+                let (kind, subtype) = item.value.split_once('/')?;
+                Some(Self {
+                    kind: kind.trim(),
+                    subtype: subtype.trim(),
+                    params: item.params,
+                    q: item.q,
+                })
+            })
+            .collect()
+    }
+
+    /// How specifically this range matches the media type `offer`, or `None`
+    /// if it doesn't: `*/*` < `type/*` < `type/subtype` < each matching
+    /// parameter on top.
+    pub fn matches(&self, offer: &str) -> Option<usize> {
+        let mut parts = offer.split(';');
+        let (kind, subtype) = parts.next()?.trim().split_once('/')?;
+        let params: Vec<(&str, &str)> = parts
+            .filter_map(|p| p.split_once('='))
+            .map(|(k, v)| (k.trim(), v.trim().trim_matches('"')))
+            .collect();
+
+        match (self.kind, self.subtype) {
+            ("*", "*") => Some(0),
+            (k, "*") if k.eq_ignore_ascii_case(kind) => Some(1),
+            (k, s) if k.eq_ignore_ascii_case(kind) && s.eq_ignore_ascii_case(subtype) => {
+                let all = self.params.iter().all(|(name, value)| {
+                    params
+                        .iter()
+                        .any(|(n, v)| n.eq_ignore_ascii_case(name) && v == value)
+                });
+                all.then_some(2 + self.params.len())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Picks the offer with the highest weight, where each offer is weighed by
+/// the most specific range that matches it. Ties go to the more specific
+/// match, then to the earlier offer.
+fn best<'a, R>(
+    ranges: &[R],
+    offers: &[&'a str],
+    q: impl Fn(&R) -> f32,
+    specificity: impl Fn(&R, &str) -> Option<usize>,
+) -> Option<&'a str> {
+    let mut best: Option<(&str, f32, usize)> = None;
+
+    for &offer in offers {
+        let Some((spec, weight)) = ranges
+            .iter()
+            .filter_map(|r| specificity(r, offer).map(|s| (s, q(r))))
+            .max_by_key(|(s, _)| *s)
+        else {
+            continue;
+        };
+
+        if weight > 0.0 && best.is_none_or(|(_, bq, bs)| weight > bq || (weight == bq && spec > bs))
+        {
+            best = Some((offer, weight, spec));
+        }
+    }
+
+    best.map(|(offer, ..)| offer)
+}
+
+fn language_matches(range: &str, offer: &str) -> Option<usize> {
+    if range == "*" {
+        return Some(0);
+    }
+
+    // Basic filtering (RFC 4647 3.3.1): `en` matches `en` and `en-US`.
+    let prefix = offer.get(..range.len())?;
+    let boundary = offer.len() == range.len() || offer.as_bytes()[range.len()] == b'-';
+    (prefix.eq_ignore_ascii_case(range) && boundary).then(|| range.split('-').count())
+}
+
+impl Request {
+    /// Picks the media type among `offers` that `Accept` ranks highest, in
+    /// the server's order of preference. Without `Accept` the first offer
+    /// wins; if the header rules them all out, that's a `406`.
+    pub fn negotiate<'a>(&self, offers: &[&'a str]) -> Result<&'a str, ServerError> {
+        let Some(accept) = self.head.headers.get("accept") else {
+            return offers.first().copied().ok_or(ServerError::NotAcceptable);
+        };
+
+        let ranges = MediaRange::parse_list(accept);
+        best(&ranges, offers, |r| r.q, MediaRange::matches).ok_or(ServerError::NotAcceptable)
+    }
+
+    /// Like [`Request::negotiate`], for language tags and `Accept-Language`.
+    pub fn negotiate_language<'a>(&self, offers: &[&'a str]) -> Result<&'a str, ServerError> {
+        self.negotiate_by("accept-language", offers, language_matches)
+    }
+
+    /// Like [`Request::negotiate`], for charsets and `Accept-Charset`.
+    pub fn negotiate_charset<'a>(&self, offers: &[&'a str]) -> Result<&'a str, ServerError> {
+        self.negotiate_by("accept-charset", offers, |range, offer| match range {
+            "*" => Some(0),
+            range => range.eq_ignore_ascii_case(offer).then_some(1),
+        })
+    }
+
+    fn negotiate_by<'a>(
+        &self,
+        header: &str,
+        offers: &[&'a str],
+        specificity: impl Fn(&str, &str) -> Option<usize>,
+    ) -> Result<&'a str, ServerError> {
+        let Some(value) = self.head.headers.get(header) else {
+            return offers.first().copied().ok_or(ServerError::NotAcceptable);
+        };
+
+        let items = parse_quality_list(value);
+        best(
+            &items,
+            offers,
+            |i| i.q,
+            |i, offer| specificity(i.value, offer),
+        )
+        .ok_or(ServerError::NotAcceptable)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(header: &str, value: &str) -> Request {
+        let mut req = Request::new();
+        req.head.headers.replace(header, value.to_string()).unwrap();
+        req
+    }
+
+    #[test]
+    fn parses_quality_lists() {
+        let items = parse_quality_list(r#"text/html;level="1";q=0.5;ext=x, , */*"#);
+
+        assert_eq!(2, items.len());
+        assert_eq!("text/html", items[0].value);
+        assert_eq!(vec![("level", "1")], items[0].params);
+        assert_eq!(0.5, items[0].q);
+        assert_eq!(("*/*", 1.0), (items[1].value, items[1].q));
+
+        assert_eq!(0.0, parse_quality_list("gzip;q=nope")[0].q);
+    }
+
+    #[test]
+    fn most_specific_range_decides() {
+        let req = request(
+            "accept",
+            "text/*;q=0.3, text/html;q=0.7, text/html;level=1, */*;q=0.5",
+        );
+
+        assert_eq!(
+            Ok("text/html;level=1"),
+            req.negotiate(&["text/html;level=1"]).map_err(drop)
+        );
+        assert_eq!(
+            Ok("text/html"),
+            req.negotiate(&["text/plain", "text/html"]).map_err(drop)
+        );
+        assert_eq!(
+            Ok("image/png"),
+            req.negotiate(&["text/plain", "image/png"]).map_err(drop)
+        );
+    }
+
+    #[test]
+    fn ties_prefer_explicit_then_server_order() {
+        let req = request("accept", "application/json, */*");
+        assert_eq!(
+            Ok("application/json"),
+            req.negotiate(&["text/html", "application/json"])
+                .map_err(drop)
+        );
+
+        let req = request("accept", "*/*");
+        assert_eq!(
+            Ok("text/html"),
+            req.negotiate(&["text/html", "application/json"])
+                .map_err(drop)
+        );
+
+        assert_eq!(
+            Ok("text/html"),
+            Request::new().negotiate(&["text/html"]).map_err(drop)
+        );
+    }
+
+    #[test]
+    fn nothing_acceptable_is_406() {
+        let req = request("accept", "image/*, text/html;q=0");
+
+        let err = req
+            .negotiate(&["text/html", "application/json"])
+            .unwrap_err();
+        assert_eq!(406, err.status_code().as_u16());
+    }
+
+    #[test]
+    fn languages_and_charsets() {
+        let req = request("accept-language", "en;q=0.8, de-CH, *;q=0.1");
+        assert_eq!(
+            Ok("de-CH"),
+            req.negotiate_language(&["en-US", "de-CH"]).map_err(drop)
+        );
+        assert_eq!(
+            Ok("en-US"),
+            req.negotiate_language(&["fr", "en-US"]).map_err(drop)
+        );
+        assert_eq!(Ok("fr"), req.negotiate_language(&["fr"]).map_err(drop));
+        assert!(
+            request("accept-language", "en")
+                .negotiate_language(&["eng"])
+                .is_err()
+        );
+
+        let req = request("accept-charset", "iso-8859-5, UTF-8;q=0.9");
+        assert_eq!(
+            Ok("iso-8859-5"),
+            req.negotiate_charset(&["utf-8", "iso-8859-5"])
+                .map_err(drop)
+        );
+        assert!(req.negotiate_charset(&["us-ascii"]).is_err());
+    }
+}
@@ -1,20 +1,43 @@
-use crate::{HTTPParsingError, Request};
-use std::collections::HashMap;
+use crate::{HTTPParsingError, Request, compression::decompress};
+use std::{collections::HashMap, io};
+
+/// Longest chunk-size or trailer line we'll wait for.
+const MAX_CHUNK_LINE: usize = 1024;
+
+/// Where a chunked body parse is at.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Chunked {
+    #[default]
+    Size,
+    Data(usize),
+    DataEnd,
+    Trailers,
+}
 
 impl Request {
     pub fn parse_body(&mut self, b: &[u8]) -> Result<(usize, bool), HTTPParsingError> {
         let (mut read, mut done) = (0, false);
 
-        if let Some(cl) = self.head.headers.get("content-length") {
+        if let Some(te) = self.head.headers.get("transfer-encoding") {
+            let chunked = te
+                .rsplit(',')
+                .next()
+                .is_some_and(|t| t.trim().eq_ignore_ascii_case("chunked"));
+            if !chunked {
+                return Err(HTTPParsingError::BadBody);
+            }
+
+            return self.parse_chunked(b);
+        } else if let Some(cl) = self.head.headers.get("content-length") {
             let n: usize = cl.parse()?;
-            let remaining = n - self.body.len();
+            let remaining = n - self.raw_body.len();
             let m = remaining.min(b.len());
-            let s = std::str::from_utf8(&b[..m])?;
-            self.body += s;
+            self.raw_body.extend_from_slice(&b[..m]);
 
             read += m;
 
-            if self.body.len() == n {
+            if self.raw_body.len() == n {
+                self.finish_body()?;
                 done = true;
             }
         } else {
@@ -23,6 +46,129 @@ impl Request {
 
         Ok((read, done))
     }
+
+    /// Decodes as much of a chunked body as `b` holds. Chunk extensions and
+    /// trailers are skipped.
+    fn parse_chunked(&mut self, b: &[u8]) -> Result<(usize, bool), HTTPParsingError> {
+        let mut read = 0;
+
+        loop {
+            let rest = &b[read..];
+
+            match self.chunked {
+                Chunked::Size => {
+                    let Some(line) = line(rest)? else {
+                        return Ok((read, false));
+                    };
+                    let size = std::str::from_utf8(line)?;
+                    let size = size.split(';').next().unwrap_or_default().trim();
+                    let size =
+                        usize::from_str_radix(size, 16).map_err(|_| HTTPParsingError::BadBody)?;
+                    read += line.len() + 2;
+
+                    if size == 0 {
+                        self.chunked = Chunked::Trailers;
+                        continue;
+                    }
+
+                    // Refuse before buffering a chunk that would go over.
+                    let total = self.raw_body.len().checked_add(size);
+                    if self
+                        .max_body_size
+                        .is_some_and(|max| total.is_none_or(|t| t as u64 > max))
+                    {
+                        return Err(HTTPParsingError::BodyTooLarge);
+                    }
+
+                    self.chunked = Chunked::Data(size);
+                }
+                Chunked::Data(remaining) => {
+                    if rest.is_empty() {
+                        return Ok((read, false));
+                    }
+
+                    let m = remaining.min(rest.len());
+                    self.raw_body.extend_from_slice(&rest[..m]);
+                    read += m;
+
+                    self.chunked = match remaining - m {
+                        0 => Chunked::DataEnd,
+                        left => Chunked::Data(left),
+                    };
+                }
+                Chunked::DataEnd => {
+                    if rest.len() < 2 {
+                        return Ok((read, false));
+                    }
+                    if &rest[..2] != b"\r\n" {
+                        return Err(HTTPParsingError::BadBody);
+                    }
+
+                    read += 2;
+                    self.chunked = Chunked::Size;
+                }
+                Chunked::Trailers => {
+                    let Some(line) = line(rest)? else {
+                        return Ok((read, false));
+                    };
+                    read += line.len() + 2;
+
+                    if line.is_empty() {
+                        self.finish_body()?;
+                        return Ok((read, true));
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Request {
+    /// Undoes any `Content-Encoding` on the body now that it's all in, and
+    /// checks that what's left is UTF-8.
+    fn finish_body(&mut self) -> Result<(), HTTPParsingError> {
+        // A coded body waits for `decode_body`, off the async workers.
+        if self.coding.is_some() {
+            return Ok(());
+        }
+
+        let raw = std::mem::take(&mut self.raw_body);
+        self.body = String::from_utf8(raw).map_err(|e| e.utf8_error())?;
+        Ok(())
+    }
+
+    /// Undoes the body's content coding on the blocking pool, then fixes
+    /// the headers to describe the decoded body.
+    pub(crate) async fn decode_body(&mut self) -> Result<(), HTTPParsingError> {
+        let Some(coding) = self.coding.take() else {
+            return Ok(());
+        };
+
+        let raw = std::mem::take(&mut self.raw_body);
+        let limit = self.max_decoded_size;
+        let decoded = tokio::task::spawn_blocking(move || decompress(coding, &raw, limit))
+            .await
+            .map_err(io::Error::from)??;
+        self.body = String::from_utf8(decoded).map_err(|e| e.utf8_error())?;
+
+        let headers = &mut self.head.headers;
+        headers.delete("content-encoding")?;
+        if headers.get("content-length").is_some() {
+            headers.replace("content-length", self.body.len().to_string())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The line at the start of `b`, without its CRLF, or `None` if it isn't
+/// complete yet.
+fn line(b: &[u8]) -> Result<Option<&[u8]>, HTTPParsingError> {
+    match b.windows(2).position(|w| w == b"\r\n") {
+        Some(i) => Ok(Some(&b[..i])),
+        None if b.len() > MAX_CHUNK_LINE => Err(HTTPParsingError::BadBody),
+        None => Ok(None),
+    }
 }
 
 /// A frame of any kind related to an HTTP stream (body).
@@ -32,6 +178,8 @@ pub struct Frame<T> {
 
 enum Kind<T> {
     Data(T),
+    // Nothing produces trailer frames yet.
+    #[allow(dead_code)]
     Trailers(HashMap<String, String>),
 }
 
@@ -12,28 +12,24 @@ impl Headers {
     pub fn parse(&mut self, b: &[u8]) -> Result<(usize, bool), HTTPParsingError> {
         let mut read: usize = 0;
         let mut done: bool = false;
-        loop {
-            if let Some(i) = b[read..]
-                .windows(SEPARATOR.len())
-                .position(|b| b == SEPARATOR)
-            {
-                if i == 0 {
-                    // EMPTY HEADER
-                    done = true;
-                    read += SEPARATOR.len();
-                    break;
-                }
-
-                let (name, value) = Self::parse_header(&b[read..read + i])?;
-
-                is_token(&name)?;
-
-                read += i + SEPARATOR.len();
-
-                self.set(name, value)?;
-            } else {
+        while let Some(i) = b[read..]
+            .windows(SEPARATOR.len())
+            .position(|b| b == SEPARATOR)
+        {
+            if i == 0 {
+                // EMPTY HEADER
+                done = true;
+                read += SEPARATOR.len();
                 break;
             }
+
+            let (name, value) = Self::parse_header(&b[read..read + i])?;
+
+            is_token(&name)?;
+
+            read += i + SEPARATOR.len();
+
+            self.set(name, value)?;
         }
 
         Ok((read, done))
@@ -64,12 +60,24 @@ impl Headers {
     }
 
     pub fn replace(&mut self, name: &str, value: String) -> Result<(), HTTPParsingError> {
-        self.0
-            .insert(name.to_ascii_lowercase().into(), value.into());
+        self.0.insert(name.to_ascii_lowercase(), value);
 
         Ok(())
     }
 
+    /// Adds `name` to the `Vary` header, unless it's already covered.
+    pub fn add_vary(&mut self, name: &str) {
+        let present = self.get("vary").is_some_and(|v| {
+            v.split(',')
+                .map(str::trim)
+                .any(|v| v == "*" || v.eq_ignore_ascii_case(name))
+        });
+
+        if !present {
+            self.set("vary".to_string(), name.to_string()).ok();
+        }
+    }
+
     pub fn delete(&mut self, name: &str) -> Result<(), HTTPParsingError> {
         let _ = self.0.remove(name);
         Ok(())
@@ -128,6 +136,7 @@ static LUT: LazyLock<[bool; 256]> = LazyLock::new(|| {
 });
 
 #[cfg(test)]
+#[allow(clippy::bool_assert_comparison)]
 mod tests {
     use super::*;
 
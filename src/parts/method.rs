@@ -8,6 +8,8 @@ use self::Inner::*;
 #[derive(PartialEq)]
 enum Inner {
     Get,
+    Head,
+    Options,
     Post,
     Put,
     Patch,
@@ -33,6 +35,8 @@ impl PartialEq<&str> for Method {
 
 impl Method {
     pub const GET: Method = Method(Get);
+    pub const HEAD: Method = Method(Head);
+    pub const OPTIONS: Method = Method(Options);
     pub const POST: Method = Method(Post);
     pub const DELETE: Method = Method(Delete);
     pub const PUT: Method = Method(Put);
@@ -47,6 +51,7 @@ impl Method {
                 _ => Err(HTTPParsingError::BadMethod),
             },
             4 => match src {
+                b"HEAD" => Ok(Method(Head)),
                 b"POST" => Ok(Method(Post)),
                 _ => Err(HTTPParsingError::BadMethod),
             },
@@ -58,6 +63,10 @@ impl Method {
                 b"DELETE" => Ok(Method(Delete)),
                 _ => Err(HTTPParsingError::BadMethod),
             },
+            7 => match src {
+                b"OPTIONS" => Ok(Method(Options)),
+                _ => Err(HTTPParsingError::BadMethod),
+            },
             _ => Err(HTTPParsingError::BadMethod),
         }
     }
@@ -65,6 +74,8 @@ impl Method {
     pub fn as_str(&self) -> &str {
         match self.0 {
             Get => "GET",
+            Head => "HEAD",
+            Options => "OPTIONS",
             Post => "POST",
             Put => "PUT",
             Delete => "DELETE",
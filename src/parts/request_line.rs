@@ -29,14 +29,14 @@ impl Request {
                 parts
                     .next()
                     .filter(|tok| !tok.is_empty() && tok.iter().all(|&c| c.is_ascii_uppercase()))
-                    .ok_or_else(|| HTTPParsingError::BadRequestLine)?,
+                    .ok_or(HTTPParsingError::BadRequestLine)?,
             )?;
 
             let uri = parts
                 .next()
                 .filter(|t| !t.is_empty())
                 .and_then(|tok| std::str::from_utf8(tok).ok())
-                .ok_or_else(|| HTTPParsingError::BadRequestLine)?
+                .ok_or(HTTPParsingError::BadRequestLine)?
                 .to_string();
 
             let version =
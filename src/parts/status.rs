@@ -1,8 +1,9 @@
+use std::fmt;
 use std::num::NonZeroU16;
 
 use crate::HTTPParsingError;
 
-#[derive(PartialEq, Debug)]
+#[derive(PartialEq, Eq, Hash, Clone, Copy, Debug)]
 pub struct StatusCode(NonZeroU16);
 
 impl Default for StatusCode {
@@ -28,12 +29,125 @@ impl StatusCode {
         let status = (a * 100) + (b * 10) + c;
         NonZeroU16::new(status)
             .map(StatusCode)
-            .ok_or_else(|| HTTPParsingError::BadStatusCode)
+            .ok_or(HTTPParsingError::BadStatusCode)
     }
 
-    pub const OK: StatusCode = StatusCode(unsafe { NonZeroU16::new_unchecked(200) });
-    pub const INTERNAL_SERVER_ERROR: StatusCode =
-        StatusCode(unsafe { NonZeroU16::new_unchecked(500) });
+    pub fn from_u16(src: u16) -> Result<StatusCode, HTTPParsingError> {
+        if !(100..1000).contains(&src) {
+            return Err(HTTPParsingError::BadStatusCode);
+        }
+
+        NonZeroU16::new(src)
+            .map(StatusCode)
+            .ok_or(HTTPParsingError::BadStatusCode)
+    }
+
+    pub fn as_u16(&self) -> u16 {
+        self.0.get()
+    }
+
+    pub fn is_informational(&self) -> bool {
+        (100..200).contains(&self.as_u16())
+    }
 
-    pub const BAD_REQUEST: StatusCode = StatusCode(unsafe { NonZeroU16::new_unchecked(400) });
+    /// Whether a response with this status can have a body: `1xx`, `204`
+    /// and `304` never do.
+    pub fn permits_body(&self) -> bool {
+        !self.is_informational() && self.as_u16() != 204 && self.as_u16() != 304
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.as_u16())
+    }
+
+    pub fn is_client_error(&self) -> bool {
+        (400..500).contains(&self.as_u16())
+    }
+
+    pub fn is_server_error(&self) -> bool {
+        (500..600).contains(&self.as_u16())
+    }
+}
+
+impl fmt::Display for StatusCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // The reason phrase is optional; unregistered codes go without.
+        write!(
+            f,
+            "{} {}",
+            self.as_u16(),
+            self.canonical_reason().unwrap_or_default()
+        )
+    }
+}
+
+macro_rules! status_codes {
+    ($(($num:expr, $konst:ident, $phrase:expr);)+) => {
+        impl StatusCode {
+            $(
+                pub const $konst: StatusCode = StatusCode(NonZeroU16::new($num).unwrap());
+            )+
+
+            pub fn canonical_reason(&self) -> Option<&'static str> {
+                match self.as_u16() {
+                    $($num => Some($phrase),)+
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+status_codes! {
+    (100, CONTINUE, "Continue");
+    (101, SWITCHING_PROTOCOLS, "Switching Protocols");
+
+    (200, OK, "OK");
+    (201, CREATED, "Created");
+    (202, ACCEPTED, "Accepted");
+    (204, NO_CONTENT, "No Content");
+    (206, PARTIAL_CONTENT, "Partial Content");
+
+    (301, MOVED_PERMANENTLY, "Moved Permanently");
+    (302, FOUND, "Found");
+    (303, SEE_OTHER, "See Other");
+    (304, NOT_MODIFIED, "Not Modified");
+    (307, TEMPORARY_REDIRECT, "Temporary Redirect");
+    (308, PERMANENT_REDIRECT, "Permanent Redirect");
+
+    (400, BAD_REQUEST, "Bad Request");
+    (401, UNAUTHORIZED, "Unauthorized");
+    (403, FORBIDDEN, "Forbidden");
+    (404, NOT_FOUND, "Not Found");
+    (405, METHOD_NOT_ALLOWED, "Method Not Allowed");
+    (406, NOT_ACCEPTABLE, "Not Acceptable");
+    (408, REQUEST_TIMEOUT, "Request Timeout");
+    (411, LENGTH_REQUIRED, "Length Required");
+    (412, PRECONDITION_FAILED, "Precondition Failed");
+    (413, CONTENT_TOO_LARGE, "Content Too Large");
+    (414, URI_TOO_LONG, "URI Too Long");
+    (415, UNSUPPORTED_MEDIA_TYPE, "Unsupported Media Type");
+    (416, RANGE_NOT_SATISFIABLE, "Range Not Satisfiable");
+    (417, EXPECTATION_FAILED, "Expectation Failed");
+    (422, UNPROCESSABLE_CONTENT, "Unprocessable Content");
+    (429, TOO_MANY_REQUESTS, "Too Many Requests");
+    (431, REQUEST_HEADER_FIELDS_TOO_LARGE, "Request Header Fields Too Large");
+
+    (500, INTERNAL_SERVER_ERROR, "Internal Server Error");
+    (501, NOT_IMPLEMENTED, "Not Implemented");
+    (502, BAD_GATEWAY, "Bad Gateway");
+    (503, SERVICE_UNAVAILABLE, "Service Unavailable");
+    (504, GATEWAY_TIMEOUT, "Gateway Timeout");
+    (505, HTTP_VERSION_NOT_SUPPORTED, "HTTP Version Not Supported");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unregistered_codes_have_an_empty_reason() {
+        assert_eq!("404 Not Found", StatusCode::NOT_FOUND.to_string());
+        assert_eq!("599 ", StatusCode::from_u16(599).unwrap().to_string());
+    }
 }
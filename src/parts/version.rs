@@ -36,11 +36,9 @@ impl Version {
         parts
             .next()
             .filter(|p| p == b"HTTP")
-            .ok_or_else(|| HTTPParsingError::BadRequestLine)?;
+            .ok_or(HTTPParsingError::BadRequestLine)?;
 
-        let version = parts
-            .next()
-            .ok_or_else(|| HTTPParsingError::BadRequestLine)?;
+        let version = parts.next().ok_or(HTTPParsingError::BadRequestLine)?;
 
         if parts.next().is_some() {
             return Err(HTTPParsingError::BadRequestLine);
@@ -0,0 +1,452 @@
+use crate::{Listener, RemoteAddr};
+use std::{
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    str,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf},
+    task::JoinSet,
+    time,
+};
+
+const V1_PREFIX: &[u8] = b"PROXY ";
+/// Longest valid v1 header, CRLF included.
+const V1_MAX_LEN: usize = 107;
+const V2_SIGNATURE: &[u8] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The addresses a PROXY protocol header reports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ProxyHeader {
+    /// The client, as seen by the proxy. `None` for `UNKNOWN` (v1), `LOCAL`
+    /// (v2), or address families we don't understand.
+    pub source: Option<SocketAddr>,
+    /// The address the client connected to on the proxy.
+    pub destination: Option<SocketAddr>,
+}
+
+/// Address of a connection accepted through a [`ProxyListener`].
+#[derive(Debug)]
+pub struct ProxiedAddr<A> {
+    /// Who we're actually talking to: usually the load balancer.
+    pub peer: A,
+    /// What the PROXY header said, or `None` if there was none.
+    pub header: Option<ProxyHeader>,
+}
+
+impl<A: RemoteAddr> RemoteAddr for ProxiedAddr<A> {
+    /// The source from the PROXY header, falling back to the peer.
+    fn socket_addr(&self) -> Option<SocketAddr> {
+        self.header
+            .and_then(|h| h.source)
+            .or_else(|| self.peer.socket_addr())
+    }
+}
+
+/// Reads a PROXY protocol (v1 or v2) header off every connection before it's
+/// served, so requests see the client's address instead of the proxy's.
+///
+/// Like the TLS handshake, headers are read in their own tasks; a peer that
+/// sends a malformed header, or none in time, is dropped. While
+/// [`ProxyListener::max_pending_headers`] are outstanding, new connections
+/// wait in the inner listener's backlog.
+pub struct ProxyListener<L: Listener> {
+    inner: L,
+    required: bool,
+    header_timeout: Duration,
+    max_pending: usize,
+    pending: JoinSet<Pending<L>>,
+}
+
+/// A connection whose header was read, or `None` if it was rejected.
+type Pending<L> = Option<(
+    Rewind<<L as Listener>::Io>,
+    ProxiedAddr<<L as Listener>::Addr>,
+)>;
+
+impl<L: Listener> ProxyListener<L> {
+    /// Every connection must start with a PROXY header.
+    pub fn new(inner: L) -> Self {
+        Self {
+            inner,
+            required: true,
+            header_timeout: Duration::from_secs(5),
+            max_pending: 256,
+            pending: JoinSet::new(),
+        }
+    }
+
+    /// Whether connections without a header are dropped. When `false`, they
+    /// are served with the peer address. Only turn this off if untrusted
+    /// clients can't reach the listener, or they can spoof their address.
+    pub fn required(mut self, required: bool) -> Self {
+        self.required = required;
+        self
+    }
+
+    /// How long a peer gets to send the header. Defaults to 5s.
+    pub fn header_timeout(mut self, timeout: Duration) -> Self {
+        self.header_timeout = timeout;
+        self
+    }
+
+    /// How many headers may be awaited at once. Defaults to 256.
+    pub fn max_pending_headers(mut self, max: usize) -> Self {
+        self.max_pending = max.max(1);
+        self
+    }
+}
+
+impl<L: Listener> Listener for ProxyListener<L> {
+    type Io = Rewind<L::Io>;
+    type Addr = ProxiedAddr<L::Addr>;
+
+    async fn accept(&mut self) -> io::Result<(Self::Io, Self::Addr)> {
+        loop {
+            tokio::select! {
+                conn = self.inner.accept(), if self.pending.len() < self.max_pending => {
+                    let (mut io, peer) = conn?;
+                    let required = self.required;
+                    let timeout = self.header_timeout;
+
+                    self.pending.spawn(async move {
+                        match time::timeout(timeout, read_header(&mut io, required)).await {
+                            Ok(Ok((header, prefix))) => {
+                                Some((Rewind::new(io, prefix), ProxiedAddr { peer, header }))
+                            }
+                            Ok(Err(err)) => {
+                                tracing::debug!("bad PROXY header from {peer:?}: {err}");
+                                None
+                            }
+                            Err(_) => {
+                                tracing::debug!("PROXY header from {peer:?} timed out");
+                                None
+                            }
+                        }
+                    });
+                }
+                Some(done) = self.pending.join_next(), if !self.pending.is_empty() => {
+                    if let Ok(Some(conn)) = done {
+                        return Ok(conn);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Reads a PROXY header off `io`, one byte at a time so nothing past it is
+/// consumed.
+///
+/// Returns the header and, if there was none, the bytes read while looking
+/// for it, which belong to the request.
+async fn read_header<I>(io: &mut I, required: bool) -> io::Result<(Option<ProxyHeader>, Vec<u8>)>
+where
+    I: AsyncRead + Unpin,
+{
+    let mut buf = Vec::with_capacity(16);
+
+    loop {
+        buf.push(io.read_u8().await?);
+
+        if buf.len() == V1_PREFIX.len() && buf == V1_PREFIX {
+            while buf.last() != Some(&b'\n') {
+                if buf.len() == V1_MAX_LEN {
+                    return Err(invalid("v1 header too long"));
+                }
+                buf.push(io.read_u8().await?);
+            }
+            return Ok((Some(parse_v1(&buf)?), Vec::new()));
+        }
+
+        if buf.len() == V2_SIGNATURE.len() && buf == V2_SIGNATURE {
+            let mut head = [0u8; 4];
+            io.read_exact(&mut head).await?;
+            let mut payload = vec![0u8; u16::from_be_bytes([head[2], head[3]]) as usize];
+            io.read_exact(&mut payload).await?;
+            return Ok((Some(parse_v2(head[0], head[1], &payload)?), Vec::new()));
+        }
+
+        if !V1_PREFIX.starts_with(&buf) && !V2_SIGNATURE.starts_with(&buf) {
+            if required {
+                return Err(invalid("missing PROXY header"));
+            }
+            return Ok((None, buf));
+        }
+    }
+}
+
+/// Parses `PROXY TCP4 <src> <dst> <sport> <dport>\r\n`, or `PROXY UNKNOWN`.
+fn parse_v1(line: &[u8]) -> io::Result<ProxyHeader> {
+    let line = line
+        .strip_suffix(b"\r\n")
+        .ok_or_else(|| invalid("v1 header must end in CRLF"))?;
+    let line = str::from_utf8(line).map_err(|_| invalid("v1 header is not ASCII"))?;
+    let mut parts = line.split(' ').skip(1);
+
+    let family = parts.next().unwrap_or_default();
+    if family == "UNKNOWN" {
+        return Ok(ProxyHeader::default());
+    }
+
+    let mut next = || {
+        parts
+            .next()
+            .ok_or_else(|| invalid("v1 header is incomplete"))
+    };
+    let (src, dst, sport, dport) = (next()?, next()?, next()?, next()?);
+    if parts.next().is_some() {
+        return Err(invalid("v1 header has trailing fields"));
+    }
+
+    let ip = |s: &str| -> io::Result<IpAddr> {
+        let ip = match family {
+            "TCP4" => s.parse::<Ipv4Addr>().map(IpAddr::V4),
+            "TCP6" => s.parse::<Ipv6Addr>().map(IpAddr::V6),
+            _ => return Err(invalid("unknown v1 address family")),
+        };
+        ip.map_err(|_| invalid("bad v1 address"))
+    };
+    let port = |s: &str| s.parse::<u16>().map_err(|_| invalid("bad v1 port"));
+
+    Ok(ProxyHeader {
+        source: Some(SocketAddr::new(ip(src)?, port(sport)?)),
+        destination: Some(SocketAddr::new(ip(dst)?, port(dport)?)),
+    })
+}
+
+/// Parses the part of a v2 header after the signature.
+fn parse_v2(version_command: u8, family: u8, payload: &[u8]) -> io::Result<ProxyHeader> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported v2 version"));
+    }
+
+    match version_command & 0x0f {
+        // LOCAL: a health check from the proxy itself.
+        0 => return Ok(ProxyHeader::default()),
+        1 => {}
+        _ => return Err(invalid("unknown v2 command")),
+    }
+
+    let port = |at: usize| u16::from_be_bytes([payload[at], payload[at + 1]]);
+
+    match family >> 4 {
+        1 if payload.len() >= 12 => {
+            let ip = |at: usize| Ipv4Addr::from(<[u8; 4]>::try_from(&payload[at..at + 4]).unwrap());
+            Ok(ProxyHeader {
+                source: Some(SocketAddr::from((ip(0), port(8)))),
+                destination: Some(SocketAddr::from((ip(4), port(10)))),
+            })
+        }
+        2 if payload.len() >= 36 => {
+            let ip =
+                |at: usize| Ipv6Addr::from(<[u8; 16]>::try_from(&payload[at..at + 16]).unwrap());
+            Ok(ProxyHeader {
+                source: Some(SocketAddr::from((ip(0), port(32)))),
+                destination: Some(SocketAddr::from((ip(16), port(34)))),
+            })
+        }
+        1 | 2 => Err(invalid("v2 address block too short")),
+        // AF_UNSPEC or AF_UNIX: nothing we can use.
+        _ => Ok(ProxyHeader::default()),
+    }
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("PROXY protocol: {msg}"))
+}
+
+/// A stream that yields some already read bytes before reading on.
+pub struct Rewind<I> {
+    prefix: Vec<u8>,
+    pos: usize,
+    io: I,
+}
+
+impl<I> Rewind<I> {
+    fn new(io: I, prefix: Vec<u8>) -> Self {
+        Self { prefix, pos: 0, io }
+    }
+
+    pub fn get_ref(&self) -> &I {
+        &self.io
+    }
+}
+
+impl<I: AsyncRead + Unpin> AsyncRead for Rewind<I> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        if this.pos < this.prefix.len() {
+            let rest = &this.prefix[this.pos..];
+            let n = rest.len().min(buf.remaining());
+            buf.put_slice(&rest[..n]);
+            this.pos += n;
+            return Poll::Ready(Ok(()));
+        }
+
+        Pin::new(&mut this.io).poll_read(cx, buf)
+    }
+}
+
+impl<I: AsyncWrite + Unpin> AsyncWrite for Rewind<I> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.get_mut().io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().io).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::{
+        io::AsyncWriteExt,
+        net::{TcpListener, TcpStream},
+    };
+
+    #[test]
+    fn parses_v1() {
+        let header = parse_v1(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 443\r\n").unwrap();
+        assert_eq!(Some("203.0.113.7:51234".parse().unwrap()), header.source);
+        assert_eq!(Some("10.0.0.1:443".parse().unwrap()), header.destination);
+
+        let header = parse_v1(b"PROXY TCP6 2001:db8::1 ::1 1 2\r\n").unwrap();
+        assert_eq!(Some("[2001:db8::1]:1".parse().unwrap()), header.source);
+
+        assert_eq!(
+            ProxyHeader::default(),
+            parse_v1(b"PROXY UNKNOWN ffff::1 ::1 1 2\r\n").unwrap()
+        );
+        assert!(parse_v1(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234\r\n").is_err());
+        assert!(parse_v1(b"PROXY TCP4 ::1 ::1 1 2\r\n").is_err());
+    }
+
+    #[test]
+    fn parses_v2() {
+        let mut payload = vec![203, 0, 113, 7, 10, 0, 0, 1];
+        payload.extend(51234u16.to_be_bytes());
+        payload.extend(443u16.to_be_bytes());
+        // A TLV we don't care about.
+        payload.extend([0x04, 0x00, 0x01, 0xff]);
+
+        let header = parse_v2(0x21, 0x11, &payload).unwrap();
+        assert_eq!(Some("203.0.113.7:51234".parse().unwrap()), header.source);
+        assert_eq!(Some("10.0.0.1:443".parse().unwrap()), header.destination);
+
+        assert_eq!(ProxyHeader::default(), parse_v2(0x20, 0x00, &[]).unwrap());
+        assert!(parse_v2(0x21, 0x11, &payload[..8]).is_err());
+        assert!(parse_v2(0x11, 0x11, &payload).is_err());
+    }
+
+    async fn accept_one(
+        required: bool,
+        send: &'static [u8],
+    ) -> Option<(Rewind<TcpStream>, ProxiedAddr<SocketAddr>)> {
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let mut listener = ProxyListener::new(tcp)
+            .required(required)
+            .header_timeout(Duration::from_millis(200));
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(send).await.unwrap();
+
+        time::timeout(Duration::from_millis(500), listener.accept())
+            .await
+            .ok()
+            .map(|conn| conn.unwrap())
+    }
+
+    #[tokio::test]
+    async fn replaces_the_peer_address() {
+        let (mut io, addr) = accept_one(
+            true,
+            b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 80\r\nGET / HTTP/1.1\r\n",
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            Some("203.0.113.7:51234".parse().unwrap()),
+            addr.socket_addr()
+        );
+
+        let mut rest = [0u8; 16];
+        io.read_exact(&mut rest).await.unwrap();
+        assert_eq!(b"GET / HTTP/1.1\r\n", &rest);
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_header_when_required() {
+        assert!(accept_one(true, b"GET / HTTP/1.1\r\n").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn passes_through_without_header_when_optional() {
+        let (mut io, addr) = accept_one(false, b"GET / HTTP/1.1\r\n").await.unwrap();
+
+        assert_eq!(addr.peer.socket_addr(), addr.socket_addr());
+
+        let mut rest = [0u8; 16];
+        io.read_exact(&mut rest).await.unwrap();
+        assert_eq!(b"GET / HTTP/1.1\r\n", &rest);
+    }
+
+    #[tokio::test]
+    async fn caps_pending_headers() {
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let mut listener = ProxyListener::new(tcp)
+            .header_timeout(Duration::from_millis(200))
+            .max_pending_headers(1);
+
+        // A peer that never sends its header fills the only slot...
+        let _stalled = TcpStream::connect(addr).await.unwrap();
+        let waiting = listener.accept();
+        assert!(
+            time::timeout(Duration::from_millis(50), waiting)
+                .await
+                .is_err()
+        );
+
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client
+            .write_all(b"PROXY TCP4 203.0.113.7 10.0.0.1 51234 80\r\n")
+            .await
+            .unwrap();
+        let waiting = listener.accept();
+        assert!(
+            time::timeout(Duration::from_millis(50), waiting)
+                .await
+                .is_err()
+        );
+
+        // ...until it times out.
+        let (_, addr) = time::timeout(Duration::from_millis(500), listener.accept())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            Some("203.0.113.7:51234".parse().unwrap()),
+            addr.socket_addr()
+        );
+    }
+}
@@ -0,0 +1,306 @@
+use crate::{Body, IntoResponse, Request, Response, ServerError, StatusCode};
+use std::{
+    ops::Range,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// More ranges than this in one request and we send the whole thing instead.
+const MAX_RANGES: usize = 16;
+
+/// Why a `Range` header can't be honoured.
+#[derive(Debug, PartialEq, Eq)]
+enum RangeError {
+    /// Not something we understand; the header is ignored.
+    Ignore,
+    /// Well-formed, but none of it overlaps the body.
+    Unsatisfiable,
+}
+
+/// Answers `req`'s `Range` header from a full `200` response, turning it
+/// into a `206 Partial Content` (`multipart/byteranges` for several
+/// ranges) or a `416 Range Not Satisfiable`.
+///
+/// Anything other than a `GET` answered with a `200` passes through, as do
+/// requests whose `If-Range` doesn't match the response's validators.
+pub fn apply_range(req: &Request, mut res: Response) -> Response {
+    res.head
+        .headers
+        .replace("accept-ranges", "bytes".to_string())
+        .ok();
+
+    if req.head.method != "GET" || res.head.status != StatusCode::OK {
+        return res;
+    }
+    let Some(range) = req.head.headers.get("range") else {
+        return res;
+    };
+    if !if_range_matches(req, &res) {
+        return res;
+    }
+
+    let len = res.body.len();
+    match parse_range(range, len) {
+        Ok(ranges) => partial(res, ranges).unwrap_or_else(|err| err.into_response()),
+        Err(RangeError::Ignore) => res,
+        Err(RangeError::Unsatisfiable) => {
+            let mut res = Response::new(Some(Body::default()));
+            res.head.status = StatusCode::RANGE_NOT_SATISFIABLE;
+            res.head
+                .headers
+                .replace("content-range", format!("bytes */{len}"))
+                .ok();
+            res
+        }
+    }
+}
+
+/// `If-Range` holds either a strong ETag or the exact `Last-Modified` date
+/// of the representation the client has part of.
+fn if_range_matches(req: &Request, res: &Response) -> bool {
+    let Some(validator) = req.head.headers.get("if-range") else {
+        return true;
+    };
+    let validator = validator.trim();
+    let headers = &res.head.headers;
+
+    if validator.starts_with('"') {
+        headers.get("etag").is_some_and(|etag| etag == validator)
+    } else {
+        headers
+            .get("last-modified")
+            .is_some_and(|date| date == validator)
+    }
+}
+
+/// Parses `bytes=0-99,200-,-50` against a body of `len` bytes into sorted,
+/// merged ranges.
+fn parse_range(header: &str, len: u64) -> Result<Vec<Range<u64>>, RangeError> {
+    let (unit, specs) = header.split_once('=').ok_or(RangeError::Ignore)?;
+    if !unit.trim().eq_ignore_ascii_case("bytes") {
+        return Err(RangeError::Ignore);
+    }
+
+    let mut ranges = Vec::new();
+    for spec in specs.split(',') {
+        let (first, last) = spec.trim().split_once('-').ok_or(RangeError::Ignore)?;
+        let num = |s: &str| s.parse::<u64>().map_err(|_| RangeError::Ignore);
+
+        let range = match (first, last) {
+            ("", suffix) => {
+                let n = num(suffix)?;
+                len.saturating_sub(n)..len
+            }
+            (first, "") => num(first)?..len,
+            (first, last) => {
+                let (first, last) = (num(first)?, num(last)?);
+                if last < first {
+                    return Err(RangeError::Ignore);
+                }
+                first..last.saturating_add(1).min(len)
+            }
+        };
+
+        if range.start < range.end {
+            ranges.push(range);
+        }
+    }
+
+    if ranges.is_empty() {
+        return Err(RangeError::Unsatisfiable);
+    }
+    if ranges.len() > MAX_RANGES {
+        return Err(RangeError::Ignore);
+    }
+
+    ranges.sort_by_key(|r| r.start);
+    let mut merged: Vec<Range<u64>> = Vec::with_capacity(ranges.len());
+    for range in ranges {
+        match merged.last_mut() {
+            Some(last) if range.start <= last.end => last.end = last.end.max(range.end),
+            _ => merged.push(range),
+        }
+    }
+
+    Ok(merged)
+}
+
+fn partial(res: Response, ranges: Vec<Range<u64>>) -> Result<Response, ServerError> {
+    let len = res.body.len();
+    let mut out = Response::default();
+    out.head.status = StatusCode::PARTIAL_CONTENT;
+    out.head.headers = res.head.headers;
+
+    let content_range = |r: &Range<u64>| format!("bytes {}-{}/{len}", r.start, r.end - 1);
+
+    if let [range] = ranges.as_slice() {
+        out.body = res.body.slice(range.clone())?;
+        out.head
+            .headers
+            .replace("content-range", content_range(range))?;
+    } else {
+        let boundary = boundary();
+        let content_type = out
+            .head
+            .headers
+            .get("content-type")
+            .cloned()
+            .unwrap_or_else(|| "application/octet-stream".to_string());
+
+        let mut parts = Vec::with_capacity(ranges.len() * 2 + 1);
+        for range in &ranges {
+            parts.push(Body::from(format!(
+                "\r\n--{boundary}\r\ncontent-type: {content_type}\r\ncontent-range: {}\r\n\r\n",
+                content_range(range)
+            )));
+            parts.push(res.body.slice(range.clone())?);
+        }
+        parts.push(Body::from(format!("\r\n--{boundary}--\r\n")));
+
+        out.body = Body::concat(parts);
+        out.head.headers.replace(
+            "content-type",
+            format!("multipart/byteranges; boundary={boundary}"),
+        )?;
+    }
+
+    out.head
+        .headers
+        .replace("content-length", out.body.len().to_string())?;
+
+    Ok(out)
+}
+
+/// A boundary that won't turn up in the parts. It only has to be unlikely,
+/// not unguessable.
+fn boundary() -> String {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or_default();
+
+    format!(
+        "{:016x}{:08x}",
+        nanos,
+        COUNTER.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Encode;
+
+    fn get(range: &str) -> Request {
+        let mut req = Request::new();
+        req.head
+            .headers
+            .replace("range", range.to_string())
+            .unwrap();
+        req
+    }
+
+    fn digits() -> Response {
+        Response::new(Some("0123456789"))
+            .content_type("text/plain")
+            .unwrap()
+    }
+
+    async fn body(res: &Response) -> String {
+        let mut out = Vec::new();
+        res.body.write(&mut out).await.unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    fn ranges(header: &str) -> Result<Vec<(u64, u64)>, RangeError> {
+        parse_range(header, 10).map(|r| r.into_iter().map(|r| (r.start, r.end)).collect())
+    }
+
+    #[test]
+    fn parses_ranges() {
+        assert_eq!(Ok(vec![(0, 5)]), ranges("bytes=0-4"));
+        assert_eq!(Ok(vec![(7, 10)]), ranges("bytes=7-"));
+        assert_eq!(Ok(vec![(7, 10)]), ranges("bytes=-3"));
+        assert_eq!(Ok(vec![(0, 10)]), ranges("bytes=-30"));
+        assert_eq!(Ok(vec![(8, 10)]), ranges("bytes=8-100"));
+        assert_eq!(Ok(vec![(0, 6)]), ranges("bytes=0-2, 1-5"));
+        assert_eq!(Ok(vec![(0, 1), (5, 6)]), ranges("bytes=5-5,0-0"));
+
+        assert_eq!(Err(RangeError::Unsatisfiable), ranges("bytes=10-"));
+        assert_eq!(Err(RangeError::Unsatisfiable), ranges("bytes=-0"));
+        assert_eq!(Err(RangeError::Ignore), ranges("bytes=5-1"));
+        assert_eq!(Err(RangeError::Ignore), ranges("items=0-1"));
+        assert_eq!(Err(RangeError::Ignore), ranges("bytes=a-b"));
+    }
+
+    #[tokio::test]
+    async fn single_range() {
+        let res = apply_range(&get("bytes=2-4"), digits());
+
+        assert_eq!(StatusCode::PARTIAL_CONTENT, res.head.status);
+        assert_eq!(
+            "bytes 2-4/10",
+            res.head.headers.get("content-range").unwrap()
+        );
+        assert_eq!("3", res.head.headers.get("content-length").unwrap());
+        assert_eq!("bytes", res.head.headers.get("accept-ranges").unwrap());
+        assert_eq!("234", body(&res).await);
+    }
+
+    #[tokio::test]
+    async fn multiple_ranges() {
+        let res = apply_range(&get("bytes=0-1,8-"), digits());
+        let content_type = res.head.headers.get("content-type").unwrap();
+        let boundary = content_type
+            .strip_prefix("multipart/byteranges; boundary=")
+            .unwrap();
+
+        let expected = format!(
+            "\r\n--{boundary}\r\ncontent-type: text/plain\r\ncontent-range: bytes 0-1/10\r\n\r\n01\
+             \r\n--{boundary}\r\ncontent-type: text/plain\r\ncontent-range: bytes 8-9/10\r\n\r\n89\
+             \r\n--{boundary}--\r\n"
+        );
+        assert_eq!(StatusCode::PARTIAL_CONTENT, res.head.status);
+        assert_eq!(
+            expected.len().to_string(),
+            *res.head.headers.get("content-length").unwrap()
+        );
+        assert_eq!(expected, body(&res).await);
+    }
+
+    #[test]
+    fn unsatisfiable_range() {
+        let res = apply_range(&get("bytes=20-"), digits());
+
+        assert_eq!(StatusCode::RANGE_NOT_SATISFIABLE, res.head.status);
+        assert_eq!("bytes */10", res.head.headers.get("content-range").unwrap());
+    }
+
+    #[test]
+    fn if_range_mismatch_sends_everything() {
+        let mut req = get("bytes=0-1");
+        req.head
+            .headers
+            .replace("if-range", "\"old\"".to_string())
+            .unwrap();
+
+        let mut res = digits();
+        res.head
+            .headers
+            .replace("etag", "\"new\"".to_string())
+            .unwrap();
+        assert_eq!(StatusCode::OK, apply_range(&req, res).head.status);
+
+        let mut res = digits();
+        res.head
+            .headers
+            .replace("etag", "\"old\"".to_string())
+            .unwrap();
+        assert_eq!(
+            StatusCode::PARTIAL_CONTENT,
+            apply_range(&req, res).head.status
+        );
+    }
+}
@@ -1,5 +1,10 @@
-use crate::{HTTPParsingError, Headers, Method, ParserState, Version};
-use std::fmt::{self};
+use crate::{
+    Chunked, ClientInfo, ContentCoding, HTTPParsingError, Headers, Method, ParserState, Version,
+};
+use std::{
+    fmt::{self},
+    net::SocketAddr,
+};
 use tokio::io::{AsyncRead, AsyncReadExt};
 
 #[derive(Default)]
@@ -7,6 +12,17 @@ pub struct Request {
     pub head: Parts,
     pub body: String,
     pub state: ParserState,
+    pub remote_addr: Option<SocketAddr>,
+    pub client_info: ClientInfo,
+    /// Upper bound on the body, enforced while it's being read.
+    pub(crate) max_body_size: Option<u64>,
+    pub(crate) chunked: Chunked,
+    /// The body as it arrives, before decoding into `body`.
+    pub(crate) raw_body: Vec<u8>,
+    /// The `Content-Encoding` to undo once the body is in.
+    pub(crate) coding: Option<ContentCoding>,
+    /// Upper bound on the body after decompression.
+    pub(crate) max_decoded_size: Option<u64>,
 }
 
 #[derive(Default)]
@@ -45,6 +61,17 @@ impl Request {
         Self::default()
     }
 
+    /// The client's address: the peer of the connection, or the address
+    /// reported by a proxy in front of it.
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.remote_addr
+    }
+
+    /// The client behind any trusted proxies. See [`ClientInfo`].
+    pub fn client_info(&self) -> &ClientInfo {
+        &self.client_info
+    }
+
     pub fn done(&self) -> bool {
         self.state == ParserState::Done
     }
@@ -61,15 +88,27 @@ impl Request {
             }
             buf_len += n;
 
-            let read = req.parse(&buf[..buf_len])?;
-            buf.copy_within(read..buf_len, 0);
-            buf_len -= read;
+            loop {
+                let read = req.parse(&buf[..buf_len])?;
+                buf.copy_within(read..buf_len, 0);
+                buf_len -= read;
+
+                if read == 0 || req.done() {
+                    break;
+                }
+            }
+        }
+
+        if !req.done() && req.state != ParserState::Init {
+            return Err(HTTPParsingError::UnexpectedEof);
         }
 
         Ok(req)
     }
 
-    fn parse(&mut self, data: &[u8]) -> Result<usize, HTTPParsingError> {
+    /// Parses as much of `data` as possible, returning how many bytes were
+    /// consumed. Pauses once the head is complete.
+    pub(crate) fn parse(&mut self, data: &[u8]) -> Result<usize, HTTPParsingError> {
         let mut read: usize = 0;
         loop {
             let current_data = &data[read..];
@@ -82,7 +121,7 @@ impl Request {
                         break;
                     }
 
-                    let request_line = rl.ok_or_else(|| HTTPParsingError::BadRequestLine)?;
+                    let request_line = rl.ok_or(HTTPParsingError::BadRequestLine)?;
 
                     self.head.method = request_line.method;
                     self.head.version = request_line.version;
@@ -100,7 +139,10 @@ impl Request {
                     }
                     read += n;
                     if done {
+                        // Stop so the caller can look at the head, e.g. to
+                        // pick a body size limit, before the body is parsed.
                         self.state = ParserState::Body;
+                        break;
                     }
                 }
                 ParserState::Body => {
@@ -110,7 +152,7 @@ impl Request {
                         self.state = ParserState::Done;
                     }
 
-                    if n == 0 || current_data.len() == 0 {
+                    if n == 0 || current_data.is_empty() {
                         break;
                     }
 
@@ -121,7 +163,7 @@ impl Request {
                 ParserState::Error => return Err(HTTPParsingError::Parser),
             }
         }
-        return Ok(read);
+        Ok(read)
     }
 }
 #[cfg(test)]
@@ -264,6 +306,63 @@ mod tests {
         assert!(r.is_err());
     }
 
+    #[tokio::test]
+    async fn good_parse_chunked_body() {
+        let r = Request::from_reader(ChunkReader::new(
+            "POST /submit HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n6;ext=1\r\nhello \r\n7\r\nworld!\n\r\n0\r\nX-Trailer: yes\r\n\r\n",
+            3,
+        ))
+        .await
+        .unwrap();
+
+        assert!(r.done());
+        assert_eq!("hello world!\n", r.body);
+    }
+
+    #[tokio::test]
+    async fn decodes_compressed_body() {
+        // Two gzip members, as `cat a.gz b.gz` would make.
+        let mut body = Vec::new();
+        for part in [r#"{"name":"#, r#""café"}"#] {
+            let mut gzip = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::fast());
+            std::io::Write::write_all(&mut gzip, part.as_bytes()).unwrap();
+            body.extend(gzip.finish().unwrap());
+        }
+
+        let mut data = format!(
+            "POST / HTTP/1.1\r\nContent-Encoding: gzip\r\nContent-Length: {}\r\n\r\n",
+            body.len()
+        )
+        .into_bytes();
+        data.extend_from_slice(&body);
+
+        let mut r = Request::new();
+        let n = r.parse(&data).unwrap();
+        // What the connection does once it has seen the head, and the body.
+        r.coding = Some(ContentCoding::Gzip);
+        r.parse(&data[n..]).unwrap();
+        r.decode_body().await.unwrap();
+
+        assert!(r.done());
+        assert_eq!(r#"{"name":"café"}"#, r.body);
+        assert_eq!(None, r.head.headers.get("content-encoding"));
+        assert_eq!(
+            r.body.len().to_string(),
+            *r.head.headers.get("content-length").unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn bad_parse_chunked_body() {
+        let r = Request::from_reader(ChunkReader::new(
+            "POST /submit HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n3\r\nhello\r\n0\r\n\r\n",
+            3,
+        ))
+        .await;
+
+        assert!(r.is_err());
+    }
+
     #[tokio::test]
     async fn bad_parse_headers() {
         let r = Request::from_reader(ChunkReader::new(
@@ -1,15 +1,171 @@
-use crate::{Headers, ServerError, StatusCode, Version};
+use crate::{ContentCoding, Headers, Request, ServerError, StatusCode, Version};
 use bytes::Bytes;
 use core::fmt;
-use std::fs;
+use std::{fs::File, io, ops::Range};
+
+const TEXT_PLAIN: &str = "text/plain; charset=utf-8";
+const TEXT_HTML: &str = "text/html; charset=utf-8";
+const OCTET_STREAM: &str = "application/octet-stream";
+const PROBLEM_JSON: &str = "application/problem+json";
 
 #[derive(Default)]
 pub struct Response {
     pub head: Parts,
-    pub body: Bytes,
+    pub body: Body,
     pub trailers: Headers,
 }
 
+/// A response body: bytes in memory, or part of a file streamed from disk
+/// as it's written.
+pub struct Body(Inner);
+
+enum Inner {
+    Full(Bytes),
+    File {
+        file: File,
+        offset: u64,
+        len: u64,
+    },
+    /// Several bodies back to back, none of them a `Concat` itself.
+    Concat(Vec<Body>),
+    /// Another body, compressed as it's written.
+    Compressed {
+        body: Box<Body>,
+        coding: ContentCoding,
+    },
+}
+
+/// A piece of a [`Body`], as the encoder sees it.
+pub(crate) enum Segment<'a> {
+    Bytes(&'a Bytes),
+    File {
+        file: &'a File,
+        offset: u64,
+        len: u64,
+    },
+    Compressed {
+        body: &'a Body,
+        coding: ContentCoding,
+    },
+}
+
+impl Default for Body {
+    fn default() -> Self {
+        Self(Inner::Full(Bytes::new()))
+    }
+}
+
+impl<T: Into<Bytes>> From<T> for Body {
+    fn from(bytes: T) -> Self {
+        Self(Inner::Full(bytes.into()))
+    }
+}
+
+impl fmt::Debug for Body {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.0 {
+            Inner::Full(bytes) => bytes.fmt(f),
+            Inner::File { offset, len, .. } => {
+                write!(f, "<file, {len} bytes from {offset}>")
+            }
+            Inner::Concat(parts) => f.debug_list().entries(parts).finish(),
+            Inner::Compressed { body, coding } => write!(f, "<{}: {body:?}>", coding.as_str()),
+        }
+    }
+}
+
+impl Body {
+    /// `len` bytes of `file`, starting at `offset`.
+    pub fn file(file: File, offset: u64, len: u64) -> Self {
+        Self(Inner::File { file, offset, len })
+    }
+
+    /// `parts` one after another.
+    pub fn concat(parts: impl IntoIterator<Item = Body>) -> Self {
+        let mut flat = Vec::new();
+        for part in parts {
+            match part.0 {
+                Inner::Concat(inner) => flat.extend(inner),
+                _ => flat.push(part),
+            }
+        }
+
+        Self(Inner::Concat(flat))
+    }
+
+    pub(crate) fn compressed(body: Body, coding: ContentCoding) -> Self {
+        Self(Inner::Compressed {
+            body: Box::new(body),
+            coding,
+        })
+    }
+
+    /// The bytes in `range`, which must lie within the body. Bodies built
+    /// with [`Body::concat`] or compressed on the fly can't be sliced.
+    pub fn slice(&self, range: Range<u64>) -> io::Result<Body> {
+        debug_assert!(range.start <= range.end && range.end <= self.len());
+
+        match &self.0 {
+            Inner::Full(bytes) => Ok(bytes.slice(range.start as usize..range.end as usize).into()),
+            Inner::File { file, offset, .. } => Ok(Body::file(
+                file.try_clone()?,
+                offset + range.start,
+                range.end - range.start,
+            )),
+            Inner::Concat(_) | Inner::Compressed { .. } => Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "can't slice a concatenated or compressed body",
+            )),
+        }
+    }
+
+    /// The length in bytes. For a body compressed on the fly, the length
+    /// before compression.
+    pub fn len(&self) -> u64 {
+        match &self.0 {
+            Inner::Full(bytes) => bytes.len() as u64,
+            Inner::File { len, .. } => *len,
+            Inner::Concat(parts) => parts.iter().map(Body::len).sum(),
+            Inner::Compressed { body, .. } => body.len(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// The body's bytes, if it's a single chunk in memory.
+    pub fn as_bytes(&self) -> Option<&Bytes> {
+        match &self.0 {
+            Inner::Full(bytes) => Some(bytes),
+            _ => None,
+        }
+    }
+
+    pub(crate) fn segments(&self) -> Vec<Segment<'_>> {
+        match &self.0 {
+            Inner::Concat(parts) => parts.iter().map(Body::segment).collect(),
+            _ => vec![self.segment()],
+        }
+    }
+
+    fn segment(&self) -> Segment<'_> {
+        match &self.0 {
+            Inner::Full(bytes) => Segment::Bytes(bytes),
+            Inner::File { file, offset, len } => Segment::File {
+                file,
+                offset: *offset,
+                len: *len,
+            },
+            Inner::Compressed { body, coding } => Segment::Compressed {
+                body,
+                coding: *coding,
+            },
+            Inner::Concat(_) => unreachable!("concatenated bodies are flattened"),
+        }
+    }
+}
+
 #[derive(Default)]
 pub struct Parts {
     pub version: Version,
@@ -17,6 +173,31 @@ pub struct Parts {
     pub headers: Headers,
 }
 
+/// An interim 1xx response, sent ahead of the final one on the same request.
+#[derive(Debug, Default)]
+pub struct Informational {
+    pub status: StatusCode,
+    pub headers: Headers,
+}
+
+impl Informational {
+    /// Tells a client waiting on `Expect: 100-continue` to send the body.
+    pub fn continue_() -> Self {
+        Self {
+            status: StatusCode::CONTINUE,
+            headers: Headers::default(),
+        }
+    }
+
+    /// Returns `None` unless `status` is 1xx.
+    pub fn new(status: StatusCode) -> Option<Self> {
+        status.is_informational().then(|| Self {
+            status,
+            headers: Headers::default(),
+        })
+    }
+}
+
 impl fmt::Debug for Response {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         writeln!(f, "Head {:?}", self.head)?;
@@ -42,21 +223,19 @@ impl fmt::Debug for Parts {
 }
 
 impl Response {
-    pub fn new(body: Option<impl Into<Bytes>>) -> Self {
+    pub fn new(body: Option<impl Into<Body>>) -> Self {
         if let Some(b) = body {
-            let bytes = b.into();
+            let body = b.into();
             let mut r = Self::default();
             r.head
                 .headers
-                .replace("content-length", bytes.len().to_string())
+                .replace("content-length", body.len().to_string())
                 .ok();
 
-            r.body = bytes;
+            r.body = body;
             r
         } else {
-            let r = Self::default();
-
-            r
+            Self::default()
         }
     }
 
@@ -68,6 +247,15 @@ impl Response {
         Ok(self)
     }
 
+    fn with_type(mut self, content_type: &'static str) -> Self {
+        self.head
+            .headers
+            .0
+            .insert("content-type".to_string(), content_type.to_string());
+
+        self
+    }
+
     pub fn chunked(mut self) -> Result<Self, ServerError> {
         self.head.headers.delete("content-length")?;
         self.head
@@ -105,48 +293,90 @@ impl Response {
 }
 
 impl Headers {
-    fn default_headers(content_length: u16) -> Result<Headers, ServerError> {
+    fn default_headers(content_length: u64) -> Result<Headers, ServerError> {
         let mut h = Headers::new();
         h.set("Content-Length".to_string(), content_length.to_string())?;
-        h.set("Connection".to_string(), "closed".to_string())?;
-        h.set("Content-Type".to_string(), "text/plain".to_string())?;
+        h.set("Connection".to_string(), "close".to_string())?;
+        h.set("Content-Type".to_string(), TEXT_PLAIN.to_string())?;
 
         Ok(h)
     }
 }
 
+static BAD_REQUEST_PAGE: &str = include_str!("../400.html");
+static INTERNAL_SERVER_ERROR_PAGE: &str = include_str!("../500.html");
+
+impl ServerError {
+    /// Renders the error as an RFC 9457 `application/problem+json` document.
+    pub fn into_problem(self) -> Response {
+        let status = self.status_code();
+        let body = format!(
+            r#"{{"type":"about:blank","title":"{}","status":{},"detail":"{}"}}"#,
+            escape_json(status.canonical_reason().unwrap_or_default()),
+            status.as_u16(),
+            escape_json(&self.detail()),
+        );
+
+        error_response(status, body, PROBLEM_JSON)
+    }
+
+    /// Like [`IntoResponse::into_response`], but answers with a problem
+    /// details body when the request's `Accept` header prefers JSON.
+    pub fn into_response_for(self, req: &Request) -> Response {
+        match req.negotiate(&[TEXT_HTML, TEXT_PLAIN, PROBLEM_JSON, "application/json"]) {
+            Ok(PROBLEM_JSON | "application/json") => self.into_problem(),
+            _ => self.into_response(),
+        }
+    }
+}
+
 impl IntoResponse for ServerError {
     fn into_response(self) -> Response {
-        let (body, status) = match self {
-            Self::ReqwestError(err) => (err.to_string(), StatusCode::BAD_REQUEST),
-            Self::IOError(err) => (err.to_string(), StatusCode::INTERNAL_SERVER_ERROR),
-            Self::Internal => (
-                fs::read_to_string("500.html").unwrap(),
-                StatusCode::INTERNAL_SERVER_ERROR,
-            ),
-            Self::BadRequest => (
-                fs::read_to_string("400.html").unwrap(),
-                StatusCode::BAD_REQUEST,
-            ),
-            Self::Parsing(err) => (err.to_string(), StatusCode::INTERNAL_SERVER_ERROR),
-        };
-
-        let head = Parts {
-            headers: Headers::default_headers(body.len() as u16).unwrap_or_default(),
-            status,
-            ..Default::default()
-        };
+        let status = self.status_code();
 
-        let r = Response {
-            head,
-            body: body.into(),
-            trailers: Headers::default(),
+        match self {
+            Self::Internal => error_response(status, INTERNAL_SERVER_ERROR_PAGE, TEXT_HTML),
+            Self::BadRequest => error_response(status, BAD_REQUEST_PAGE, TEXT_HTML),
+            err => error_response(status, err.detail(), TEXT_PLAIN),
         }
-        .content_type("text/html")
-        .unwrap();
+    }
+}
 
-        r
+fn error_response(
+    status: StatusCode,
+    body: impl Into<Body>,
+    content_type: &'static str,
+) -> Response {
+    let body = body.into();
+
+    let head = Parts {
+        headers: Headers::default_headers(body.len()).unwrap_or_default(),
+        status,
+        ..Default::default()
+    };
+
+    Response {
+        head,
+        body,
+        trailers: Headers::default(),
+    }
+    .with_type(content_type)
+}
+
+pub(crate) fn escape_json(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
     }
+    out
 }
 
 impl IntoResponse for Response {
@@ -155,6 +385,97 @@ impl IntoResponse for Response {
     }
 }
 
+impl IntoResponse for () {
+    fn into_response(self) -> Response {
+        Response::new(Some(Bytes::new()))
+    }
+}
+
+impl IntoResponse for StatusCode {
+    fn into_response(self) -> Response {
+        let mut r = ().into_response();
+        r.head.status = self;
+        r
+    }
+}
+
+impl IntoResponse for &'static str {
+    fn into_response(self) -> Response {
+        Bytes::from_static(self.as_bytes())
+            .into_response()
+            .with_type(TEXT_PLAIN)
+    }
+}
+
+impl IntoResponse for String {
+    fn into_response(self) -> Response {
+        Bytes::from(self).into_response().with_type(TEXT_PLAIN)
+    }
+}
+
+impl IntoResponse for Vec<u8> {
+    fn into_response(self) -> Response {
+        Bytes::from(self).into_response()
+    }
+}
+
+impl IntoResponse for Body {
+    fn into_response(self) -> Response {
+        Response::new(Some(self)).with_type(OCTET_STREAM)
+    }
+}
+
+impl IntoResponse for Bytes {
+    fn into_response(self) -> Response {
+        Response::new(Some(self)).with_type(OCTET_STREAM)
+    }
+}
+
+impl<T> IntoResponse for (StatusCode, T)
+where
+    T: IntoResponse,
+{
+    fn into_response(self) -> Response {
+        let (status, body) = self;
+        let mut r = body.into_response();
+        r.head.status = status;
+        r
+    }
+}
+
+impl<T> IntoResponse for (StatusCode, Headers, T)
+where
+    T: IntoResponse,
+{
+    fn into_response(self) -> Response {
+        let (status, headers, body) = self;
+        let mut r = (status, body).into_response();
+        for (k, v) in headers.0 {
+            r.head.headers.0.insert(k, v);
+        }
+        r
+    }
+}
+
+/// An HTML response. Sets `Content-Type: text/html; charset=utf-8`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Html<T>(pub T);
+
+impl<T> IntoResponse for Html<T>
+where
+    T: Into<Bytes>,
+{
+    fn into_response(self) -> Response {
+        Response::new(Some(self.0)).with_type(TEXT_HTML)
+    }
+}
+
+impl<T> From<T> for Html<T> {
+    fn from(inner: T) -> Self {
+        Self(inner)
+    }
+}
+
 pub trait IntoResponse {
     #[must_use]
     fn into_response(self) -> Response;
@@ -172,3 +493,111 @@ where
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::HTTPParsingError;
+
+    #[test]
+    fn str_into_response() {
+        let r = "hello".into_response();
+
+        assert_eq!(StatusCode::OK, r.head.status);
+        assert_eq!("5", r.head.headers.get("content-length").unwrap());
+        assert_eq!(TEXT_PLAIN, r.head.headers.get("content-type").unwrap());
+        assert_eq!("hello", *r.body.as_bytes().unwrap());
+    }
+
+    #[test]
+    fn bytes_into_response() {
+        let r = vec![0u8, 1, 2].into_response();
+
+        assert_eq!("3", r.head.headers.get("content-length").unwrap());
+        assert_eq!(OCTET_STREAM, r.head.headers.get("content-type").unwrap());
+    }
+
+    #[test]
+    fn status_into_response() {
+        let r = StatusCode::NOT_FOUND.into_response();
+
+        assert_eq!(StatusCode::NOT_FOUND, r.head.status);
+        assert_eq!("0", r.head.headers.get("content-length").unwrap());
+        assert!(r.body.is_empty());
+    }
+
+    #[test]
+    fn tuple_into_response() {
+        let mut h = Headers::new();
+        h.set("X-Foo".to_string(), "bar".to_string()).unwrap();
+
+        let r = (StatusCode::CREATED, h, Html("<p>hi</p>")).into_response();
+
+        assert_eq!(StatusCode::CREATED, r.head.status);
+        assert_eq!("bar", r.head.headers.get("x-foo").unwrap());
+        assert_eq!("9", r.head.headers.get("content-length").unwrap());
+        assert_eq!(TEXT_HTML, r.head.headers.get("content-type").unwrap());
+    }
+
+    #[test]
+    fn parsing_errors_are_client_errors() {
+        let r = ServerError::Parsing(HTTPParsingError::BadFieldLine).into_response();
+
+        assert_eq!(StatusCode::BAD_REQUEST, r.head.status);
+        assert_eq!(TEXT_PLAIN, r.head.headers.get("content-type").unwrap());
+        assert_eq!("malformed field line", *r.body.as_bytes().unwrap());
+
+        let r = ServerError::Parsing(HTTPParsingError::UnsupportedHTTPVersion).into_response();
+        assert_eq!(StatusCode::HTTP_VERSION_NOT_SUPPORTED, r.head.status);
+    }
+
+    #[test]
+    fn error_pages_are_html() {
+        let r = ServerError::Internal.into_response();
+
+        assert_eq!(StatusCode::INTERNAL_SERVER_ERROR, r.head.status);
+        assert_eq!(TEXT_HTML, r.head.headers.get("content-type").unwrap());
+        assert_eq!(INTERNAL_SERVER_ERROR_PAGE, *r.body.as_bytes().unwrap());
+    }
+
+    #[test]
+    fn problem_details() {
+        let missing = io::Error::new(io::ErrorKind::NotFound, "/srv/www/secret.txt");
+
+        assert_eq!(
+            "bad method",
+            ServerError::from(HTTPParsingError::BadMethod).detail()
+        );
+        assert_eq!("not found", ServerError::from(missing).detail());
+        assert_eq!(
+            "IO error",
+            ServerError::from(io::Error::other("/srv")).detail()
+        );
+        assert_eq!("timed out", ServerError::Timeout.detail());
+    }
+
+    #[test]
+    fn problem_json_by_accept() {
+        let mut req = Request::new();
+        req.head
+            .headers
+            .replace("accept", "application/problem+json".to_string())
+            .unwrap();
+
+        let r = ServerError::BadRequest.into_response_for(&req);
+
+        assert_eq!(PROBLEM_JSON, r.head.headers.get("content-type").unwrap());
+        assert_eq!(
+            r#"{"type":"about:blank","title":"Bad Request","status":400,"detail":"bad request"}"#,
+            *r.body.as_bytes().unwrap()
+        );
+
+        req.head
+            .headers
+            .replace("accept", "text/html, application/json;q=0.9".to_string())
+            .unwrap();
+
+        let r = ServerError::BadRequest.into_response_for(&req);
+        assert_eq!(TEXT_HTML, r.head.headers.get("content-type").unwrap());
+    }
+}
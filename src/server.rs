@@ -1,85 +1,346 @@
-use crate::{Connection, Listener, ServerError};
-use core::pin::pin;
-use std::net::SocketAddr;
-use tokio::{net::TcpStream, signal, sync::watch};
+use crate::{
+    AcceptAction, Backoff, Builder, Config, Connection, Listener, RemoteAddr, ServerError,
+    handle_error,
+};
+use core::pin::Pin;
+use std::{
+    any::Any,
+    io,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    signal,
+    sync::{Notify, OwnedSemaphorePermit, Semaphore, watch},
+    time,
+};
 use tracing::info;
 
 pub struct Serve;
 
 impl Serve {
-    async fn handler<L>(
-        io: TcpStream,
-        signal_tx: &watch::Sender<()>,
-        close_rx: &watch::Receiver<()>,
-        remote_addr: SocketAddr,
-    ) {
-        tracing::info!("connection {remote_addr:?} accepted");
+    /// Serves `listener` with the default [`Config`] until `signal` resolves.
+    pub async fn serve<L, F>(listener: L, signal: F) -> Result<(), ServerError>
+    where
+        L: Listener,
+        F: Future<Output = ()> + Send + Sync + 'static,
+    {
+        Server::builder().build().serve_with(listener, signal).await
+    }
+}
 
-        let signal_tx = signal_tx.clone();
-        let close_rx = close_rx.clone();
+pub struct Server {
+    config: Arc<Config>,
+    connections: ConnectionCount,
+}
 
-        tokio::spawn(async move {
-            let mut conn = Connection::new(io);
+/// The number of connections a [`Server`] is currently serving.
+#[derive(Clone, Default, Debug)]
+pub struct ConnectionCount(Arc<AtomicUsize>);
 
-            let mut signal_closed = pin!(signal_tx.closed());
+impl ConnectionCount {
+    pub fn get(&self) -> usize {
+        self.0.load(Ordering::Relaxed)
+    }
 
-            loop {
-                tokio::select! {
-                    result = conn.run() => {
-                        if let Err(_err) = result {
-                            tracing::error!("failed to serve connection: {_err:#}");
-                        }
-                        break;
-                    }
-                    _ = &mut signal_closed => {
-                        tracing::info!("signal received in task, starting graceful shutdown");
-                        conn.graceful_shutdown().await;
-                        break;
-                    }
-                }
-            }
+    fn track(&self) -> Tracked {
+        self.0.fetch_add(1, Ordering::Relaxed);
+        Tracked(self.clone())
+    }
+}
 
-            drop(close_rx);
-        });
+/// Counts one connection for as long as it's alive.
+struct Tracked(ConnectionCount);
+
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        self.0.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// State shared by every accept loop of one [`Server`].
+#[derive(Clone)]
+struct Shared {
+    config: Arc<Config>,
+    signal_tx: watch::Sender<()>,
+    close_rx: watch::Receiver<()>,
+    /// Closed once the shutdown grace period runs out.
+    abort_rx: watch::Receiver<()>,
+    permits: Option<Arc<Semaphore>>,
+    connections: ConnectionCount,
+    /// Woken by an accept loop that hit a fatal error.
+    fatal: Arc<Notify>,
+}
+
+impl Server {
+    pub fn builder() -> Builder {
+        Builder::new()
+    }
+
+    pub(crate) fn new(config: Config) -> Self {
+        Self {
+            config: Arc::new(config),
+            connections: ConnectionCount::default(),
+        }
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// A live view of the number of open connections, usable while serving.
+    pub fn connections(&self) -> ConnectionCount {
+        self.connections.clone()
+    }
+
+    /// Binds every configured address and serves them until `signal` resolves.
+    pub async fn serve<F>(self, signal: F) -> Result<(), ServerError>
+    where
+        F: Future<Output = ()> + Send + Sync + 'static,
+    {
+        self.config.check()?;
+
+        let mut listeners = Vec::new();
+        for addr in self.config.addrs() {
+            let listener = self.config.tcp.bind(addr)?;
+            info!("listening on {}", listener.local_addr()?);
+            listeners.push(listener);
+        }
+
+        self.run(listeners, signal).await
     }
 
-    pub async fn serve<L, F>(mut listener: L, signal: F) -> Result<(), ServerError>
+    /// Serves an already bound `listener` until `signal` resolves, ignoring
+    /// the configured addresses. Combine several with [`Listener::or`].
+    pub async fn serve_with<L, F>(self, listener: L, signal: F) -> Result<(), ServerError>
     where
-        L: Listener<Io = TcpStream, Addr = SocketAddr>,
+        L: Listener,
+        F: Future<Output = ()> + Send + Sync + 'static,
+    {
+        self.config.check()?;
+        self.run(vec![listener], signal).await
+    }
+
+    async fn run<L, F>(self, listeners: Vec<L>, signal: F) -> Result<(), ServerError>
+    where
+        L: Listener,
         F: Future<Output = ()> + Send + Sync + 'static,
     {
         let (signal_tx, signal_rx) = watch::channel(());
+        let fatal = Arc::new(Notify::new());
 
+        let stop = fatal.clone();
         tokio::spawn(async move {
-            signal.await;
-            info!("received graceful shutdown. telling tasks to shut down");
+            tokio::select! {
+                _ = signal => info!("received graceful shutdown. telling tasks to shut down"),
+                _ = stop.notified() => info!("accepting failed. telling tasks to shut down"),
+            }
             drop(signal_rx);
         });
 
         let (close_tx, close_rx) = watch::channel(());
+        let (abort_tx, abort_rx) = watch::channel(());
+
+        let shared = Shared {
+            permits: self
+                .config
+                .max_connections
+                .map(|n| Arc::new(Semaphore::new(n))),
+            config: self.config,
+            connections: self.connections,
+            signal_tx,
+            close_rx,
+            abort_rx,
+            fatal,
+        };
+
+        let loops: Vec<_> = listeners
+            .into_iter()
+            .map(|listener| tokio::spawn(Self::accept_loop(listener, shared.clone())))
+            .collect();
+
+        let grace = shared.config.shutdown_grace_period;
+        drop(shared);
+
+        let mut result = Ok(());
+        for l in loops {
+            match l.await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => result = Err(ServerError::IOError(err)),
+                Err(err) => tracing::error!("accept loop failed: {err}"),
+            }
+        }
+
+        info!(
+            "waiting for {} task(s) to finish",
+            close_tx.receiver_count()
+        );
+
+        let drained = match grace {
+            Some(grace) => time::timeout(grace, close_tx.closed()).await.is_ok(),
+            None => {
+                close_tx.closed().await;
+                true
+            }
+        };
+
+        if drained {
+            info!("all connections drained");
+        } else {
+            tracing::warn!(
+                "shutdown deadline elapsed, aborting {} connection(s)",
+                close_tx.receiver_count()
+            );
+            drop(abort_tx);
+            close_tx.closed().await;
+        }
+
+        result
+    }
+
+    async fn accept_loop<L>(mut listener: L, shared: Shared) -> io::Result<()>
+    where
+        L: Listener,
+    {
+        let policy = &shared.config.accept_error_policy;
+        let mut backoff = Backoff::default();
 
         loop {
-            let (io, remote_addr) = tokio::select! {
+            let permit = match &shared.permits {
+                Some(permits) => tokio::select! {
+                    permit = permits.clone().acquire_owned() => permit.ok(),
+                    _ = shared.signal_tx.closed() => break,
+                },
+                None => None,
+            };
+
+            let conn = tokio::select! {
                 conn = listener.accept() => conn,
-                _ = signal_tx.closed() => {
+                _ = shared.signal_tx.closed() => {
                     info!("signal received, not accepting new connections");
                     break;}
             };
 
-            Self::handler::<L>(io, &signal_tx, &close_rx, remote_addr).await;
+            let (io, remote_addr) = match conn {
+                Ok(conn) => conn,
+                Err(err) => match handle_error(&err, policy, &mut backoff) {
+                    AcceptAction::Retry => continue,
+                    AcceptAction::RetryAfter(delay) => {
+                        tokio::select! {
+                            _ = time::sleep(delay) => continue,
+                            _ = shared.signal_tx.closed() => break,
+                        }
+                    }
+                    AcceptAction::Fatal => {
+                        shared.fatal.notify_one();
+                        return Err(err);
+                    }
+                },
+            };
+            backoff.reset();
+
+            Self::handler(io, &shared, remote_addr, permit);
         }
 
-        drop(close_rx);
-        drop(listener);
+        Ok(())
+    }
 
-        info!(
-            "waiting for {} task(s) to finish",
-            close_tx.receiver_count()
-        );
+    fn handler<I, A>(io: I, shared: &Shared, remote_addr: A, permit: Option<OwnedSemaphorePermit>)
+    where
+        I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+        A: RemoteAddr,
+    {
+        let config = shared.config.clone();
+        let signal_tx = shared.signal_tx.clone();
+        let close_rx = shared.close_rx.clone();
+        let mut abort_rx = shared.abort_rx.clone();
 
-        close_tx.closed().await;
+        let overloaded = config
+            .soft_connection_limit
+            .is_some_and(|limit| shared.connections.get() >= limit);
+        let tracked = shared.connections.track();
 
-        Ok(())
+        if config.log_requests {
+            tracing::info!("connection {remote_addr:?} accepted");
+        }
+
+        tokio::spawn(async move {
+            let mut conn = Connection::new(io)
+                .with_config(config)
+                .with_remote_addr(remote_addr.socket_addr())
+                .with_shutdown(signal_tx);
+
+            if overloaded {
+                tracing::warn!("over the soft connection limit, rejecting {remote_addr:?}");
+                conn.abort(ServerError::Overloaded).await;
+                drop(tracked);
+                return;
+            }
+
+            tokio::select! {
+                result = CatchUnwind::new(conn.run()) => {
+                    match result {
+                        Ok(Ok(())) => {}
+                        Ok(Err(_err)) => {
+                            tracing::error!("failed to serve connection: {_err:#}");
+                        }
+                        Err(panic) => {
+                            tracing::error!(
+                                "connection {remote_addr:?} handler panicked: {}",
+                                panic_message(&*panic)
+                            );
+                            conn.abort(ServerError::Internal).await;
+                        }
+                    }
+                }
+                _ = abort_rx.changed() => {
+                    tracing::warn!("connection {remote_addr:?} aborted at shutdown deadline");
+                }
+            }
+
+            drop(tracked);
+            drop(permit);
+            drop(close_rx);
+        });
+    }
+}
+
+/// Resolves to `Err` with the panic payload if the inner future panics
+/// while being polled, so a faulty handler can't take its task down silently.
+struct CatchUnwind<F>(Pin<Box<F>>);
+
+impl<F: Future> CatchUnwind<F> {
+    fn new(fut: F) -> Self {
+        Self(Box::pin(fut))
+    }
+}
+
+impl<F: Future> Future for CatchUnwind<F> {
+    type Output = Result<F::Output, Box<dyn Any + Send>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let fut = self.0.as_mut();
+
+        match panic::catch_unwind(AssertUnwindSafe(|| fut.poll(cx))) {
+            Ok(Poll::Pending) => Poll::Pending,
+            Ok(Poll::Ready(out)) => Poll::Ready(Ok(out)),
+            Err(panic) => Poll::Ready(Err(panic)),
+        }
+    }
+}
+
+fn panic_message(panic: &(dyn Any + Send)) -> &str {
+    if let Some(s) = panic.downcast_ref::<&'static str>() {
+        s
+    } else if let Some(s) = panic.downcast_ref::<String>() {
+        s
+    } else {
+        "<non-string panic payload>"
     }
 }
 
@@ -106,3 +367,125 @@ pub async fn shutdown_signal() {
         _ = terminate => {}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        sync::oneshot,
+    };
+
+    #[tokio::test]
+    async fn catch_unwind_returns_panic_payload() {
+        let ok = CatchUnwind::new(async { 1 }).await;
+        assert_eq!(1, ok.unwrap());
+
+        let err = CatchUnwind::new(async { panic!("boom") }).await;
+        assert_eq!("boom", panic_message(&*err.unwrap_err()));
+    }
+
+    #[tokio::test]
+    async fn shutdown_aborts_connections_after_grace_period() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = oneshot::channel::<()>();
+
+        let server = Server::builder()
+            .shutdown_grace_period(Some(Duration::from_millis(50)))
+            .build();
+        let serve = tokio::spawn(server.serve_with(listener, async {
+            let _ = rx.await;
+        }));
+
+        // A request that never completes keeps its connection in flight.
+        let mut client = TcpStream::connect(addr).await.unwrap();
+        client.write_all(b"GET / HTTP/1.1\r\n").await.unwrap();
+        time::sleep(Duration::from_millis(20)).await;
+
+        tx.send(()).unwrap();
+
+        time::timeout(Duration::from_secs(1), serve)
+            .await
+            .expect("server did not stop after the grace period")
+            .unwrap()
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn soft_limit_rejects_with_503() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let server = Server::builder().soft_connection_limit(Some(1)).build();
+        let connections = server.connections();
+        tokio::spawn(server.serve_with(listener, std::future::pending()));
+
+        let _first = TcpStream::connect(addr).await.unwrap();
+        while connections.get() == 0 {
+            time::sleep(Duration::from_millis(5)).await;
+        }
+
+        let mut second = TcpStream::connect(addr).await.unwrap();
+        let mut res = String::new();
+        second.read_to_string(&mut res).await.unwrap();
+
+        assert!(res.starts_with("HTTP/1.1 503 Service Unavailable\r\n"));
+        assert_eq!(1, connections.get());
+    }
+
+    #[tokio::test]
+    async fn any_origin_with_credentials_fails_to_serve() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server = Server::builder()
+            .cors(
+                crate::Cors::new()
+                    .allow_any_origin()
+                    .allow_credentials(true),
+            )
+            .build();
+
+        let res = server.serve_with(listener, std::future::pending()).await;
+        assert!(
+            matches!(res, Err(ServerError::IOError(e)) if e.kind() == io::ErrorKind::InvalidInput)
+        );
+    }
+
+    /// A listener that fails every accept with the same error.
+    struct Failing(io::ErrorKind);
+
+    impl Listener for Failing {
+        type Io = TcpStream;
+        type Addr = ();
+
+        async fn accept(&mut self) -> io::Result<(TcpStream, ())> {
+            Err(self.0.into())
+        }
+    }
+
+    #[tokio::test]
+    async fn fatal_accept_error_stops_the_server() {
+        let server = Server::builder()
+            .accept_error_policy(
+                crate::AcceptErrorPolicy::default()
+                    .fatal_if(|e, _| e.kind() == io::ErrorKind::PermissionDenied),
+            )
+            .build();
+
+        let res = time::timeout(
+            Duration::from_secs(1),
+            server.serve_with(
+                Failing(io::ErrorKind::PermissionDenied),
+                std::future::pending(),
+            ),
+        )
+        .await
+        .expect("server kept accepting after a fatal error");
+
+        assert!(
+            matches!(res, Err(ServerError::IOError(e)) if e.kind() == io::ErrorKind::PermissionDenied)
+        );
+    }
+}
@@ -0,0 +1,260 @@
+use crate::Listener;
+use socket2::{Domain, Protocol, SockRef, Socket, TcpKeepalive, Type};
+use std::{io, net::SocketAddr, time::Duration};
+use tokio::net::{TcpListener, TcpStream};
+
+/// Socket options applied to every accepted [`TcpStream`], plus how the
+/// listening socket itself is bound.
+#[derive(Clone, Debug)]
+pub struct TcpOptions {
+    /// Disables Nagle's algorithm, so small responses go out immediately.
+    pub nodelay: bool,
+    pub keepalive: Option<Keepalive>,
+    pub send_buffer_size: Option<usize>,
+    pub recv_buffer_size: Option<usize>,
+    /// `SO_LINGER`. `None` leaves the system default.
+    pub linger: Option<Duration>,
+    /// Binds with `SO_REUSEPORT`, so several listeners, in this process or
+    /// others, can share one port and have the kernel spread connections
+    /// between them.
+    pub reuse_port: bool,
+    /// Length of the pending connection queue.
+    pub backlog: u32,
+}
+
+impl Default for TcpOptions {
+    fn default() -> Self {
+        Self {
+            nodelay: true,
+            keepalive: None,
+            send_buffer_size: None,
+            recv_buffer_size: None,
+            linger: None,
+            reuse_port: false,
+            backlog: 1024,
+        }
+    }
+}
+
+/// TCP keepalive probing of idle connections.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Keepalive {
+    /// Idle time before the first probe.
+    pub time: Duration,
+    /// Time between unanswered probes. Not supported everywhere.
+    pub interval: Option<Duration>,
+    /// Unanswered probes before the connection is dropped. Not supported
+    /// everywhere.
+    pub retries: Option<u32>,
+}
+
+impl TcpOptions {
+    pub fn nodelay(mut self, enabled: bool) -> Self {
+        self.nodelay = enabled;
+        self
+    }
+
+    pub fn keepalive(mut self, keepalive: Option<Keepalive>) -> Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    pub fn send_buffer_size(mut self, bytes: Option<usize>) -> Self {
+        self.send_buffer_size = bytes;
+        self
+    }
+
+    pub fn recv_buffer_size(mut self, bytes: Option<usize>) -> Self {
+        self.recv_buffer_size = bytes;
+        self
+    }
+
+    pub fn linger(mut self, t: Option<Duration>) -> Self {
+        self.linger = t;
+        self
+    }
+
+    pub fn reuse_port(mut self, enabled: bool) -> Self {
+        self.reuse_port = enabled;
+        self
+    }
+
+    pub fn backlog(mut self, n: u32) -> Self {
+        self.backlog = n;
+        self
+    }
+
+    /// Binds a listener that applies these options to every connection.
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<TcpIncoming> {
+        let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+        // On Unix this only lets a restart bind past connections in
+        // TIME_WAIT. Windows would let another process take the port.
+        #[cfg(unix)]
+        socket.set_reuse_address(true)?;
+        if self.reuse_port {
+            #[cfg(unix)]
+            socket.set_reuse_port(true)?;
+            #[cfg(not(unix))]
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "SO_REUSEPORT is not supported on this platform",
+            ));
+        }
+        if addr.is_ipv6() {
+            socket.set_only_v6(true)?;
+        }
+        socket.set_nonblocking(true)?;
+        socket.bind(&addr.into())?;
+        socket.listen(self.backlog.try_into().unwrap_or(i32::MAX))?;
+
+        Ok(TcpIncoming::new(
+            TcpListener::from_std(socket.into())?,
+            self.clone(),
+        ))
+    }
+
+    /// Applies the per-connection options to `stream`.
+    pub fn apply(&self, stream: &TcpStream) -> io::Result<()> {
+        let socket = SockRef::from(stream);
+
+        socket.set_tcp_nodelay(self.nodelay)?;
+        if let Some(keepalive) = &self.keepalive {
+            socket.set_tcp_keepalive(&keepalive.to_socket2())?;
+        }
+        if let Some(bytes) = self.send_buffer_size {
+            socket.set_send_buffer_size(bytes)?;
+        }
+        if let Some(bytes) = self.recv_buffer_size {
+            socket.set_recv_buffer_size(bytes)?;
+        }
+        if self.linger.is_some() {
+            socket.set_linger(self.linger)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Keepalive {
+    pub fn new(time: Duration) -> Self {
+        Self {
+            time,
+            interval: None,
+            retries: None,
+        }
+    }
+
+    pub fn interval(mut self, t: Duration) -> Self {
+        self.interval = Some(t);
+        self
+    }
+
+    pub fn retries(mut self, n: u32) -> Self {
+        self.retries = Some(n);
+        self
+    }
+
+    fn to_socket2(self) -> TcpKeepalive {
+        #[allow(unused_mut)]
+        let mut keepalive = TcpKeepalive::new().with_time(self.time);
+
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "macos",
+            target_os = "ios",
+            target_os = "freebsd",
+            windows
+        ))]
+        if let Some(interval) = self.interval {
+            keepalive = keepalive.with_interval(interval);
+        }
+
+        #[cfg(any(
+            target_os = "linux",
+            target_os = "android",
+            target_os = "macos",
+            target_os = "ios",
+            target_os = "freebsd"
+        ))]
+        if let Some(retries) = self.retries {
+            keepalive = keepalive.with_retries(retries);
+        }
+
+        keepalive
+    }
+}
+
+/// A [`TcpListener`] that applies [`TcpOptions`] to what it accepts.
+///
+/// A bare `TcpListener` gets the default options, which only turn on
+/// `TCP_NODELAY`.
+#[derive(Debug)]
+pub struct TcpIncoming {
+    listener: TcpListener,
+    options: TcpOptions,
+}
+
+impl TcpIncoming {
+    pub fn new(listener: TcpListener, options: TcpOptions) -> Self {
+        Self { listener, options }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+}
+
+impl Listener for TcpIncoming {
+    type Io = TcpStream;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> io::Result<(Self::Io, Self::Addr)> {
+        let (stream, addr) = self.listener.accept().await?;
+
+        // A connection missing an option is still worth serving.
+        if let Err(err) = self.options.apply(&stream) {
+            tracing::debug!("failed to set socket options for {addr}: {err}");
+        }
+
+        Ok((stream, addr))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn applies_options_to_accepted_streams() {
+        let options = TcpOptions::default()
+            .keepalive(Some(
+                Keepalive::new(Duration::from_secs(30)).interval(Duration::from_secs(5)),
+            ))
+            .recv_buffer_size(Some(64 * 1024));
+        let mut listener = options.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let _client = TcpStream::connect(addr).await.unwrap();
+        let (stream, _) = Listener::accept(&mut listener).await.unwrap();
+        let socket = SockRef::from(&stream);
+
+        assert!(socket.tcp_nodelay().unwrap());
+        assert!(socket.keepalive().unwrap());
+        assert!(socket.recv_buffer_size().unwrap() >= 64 * 1024);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn reuse_port_lets_two_listeners_share_a_port() {
+        let options = TcpOptions::default().reuse_port(true);
+        let first = options.bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = first.local_addr().unwrap();
+
+        let second = options.bind(addr).unwrap();
+        assert_eq!(addr, second.local_addr().unwrap());
+
+        let without = TcpOptions::default().bind(addr);
+        assert!(without.is_err());
+    }
+}
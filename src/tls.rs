@@ -0,0 +1,426 @@
+use crate::Listener;
+use std::{
+    collections::HashMap,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+use tokio::{task::JoinSet, time};
+use tokio_rustls::{
+    TlsAcceptor,
+    rustls::{
+        ServerConfig,
+        crypto::CryptoProvider,
+        pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
+        server::{ClientHello, ResolvesServerCert},
+        sign::CertifiedKey,
+    },
+    server::TlsStream,
+};
+
+/// Where to find the certificates a [`TlsListener`] serves.
+///
+/// Every file is PEM: a certificate chain with the end-entity certificate
+/// first, and a private key.
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    cert: PathBuf,
+    key: PathBuf,
+    sni: Vec<(String, PathBuf, PathBuf)>,
+    alpn: Vec<Vec<u8>>,
+}
+
+impl TlsConfig {
+    /// The certificate served when the client sends no SNI, or an unknown name.
+    pub fn new(cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        Self {
+            cert: cert.into(),
+            key: key.into(),
+            sni: Vec::new(),
+            alpn: vec![b"http/1.1".to_vec()],
+        }
+    }
+
+    /// Serves a different certificate to clients asking for `server_name`.
+    pub fn sni(
+        mut self,
+        server_name: impl Into<String>,
+        cert: impl Into<PathBuf>,
+        key: impl Into<PathBuf>,
+    ) -> Self {
+        self.sni.push((
+            server_name.into().to_ascii_lowercase(),
+            cert.into(),
+            key.into(),
+        ));
+        self
+    }
+
+    /// Protocols advertised via ALPN, most preferred first. Defaults to
+    /// `http/1.1`.
+    pub fn alpn(mut self, protocols: &[&[u8]]) -> Self {
+        self.alpn = protocols.iter().map(|p| p.to_vec()).collect();
+        self
+    }
+
+    /// Reads every certificate and key from disk.
+    pub fn load(&self) -> io::Result<Arc<ServerConfig>> {
+        let provider = ServerConfig::builder().crypto_provider().clone();
+
+        let mut by_name = HashMap::new();
+        for (name, cert, key) in &self.sni {
+            by_name.insert(name.clone(), load_key(&provider, cert, key)?);
+        }
+
+        let resolver = SniResolver {
+            default: load_key(&provider, &self.cert, &self.key)?,
+            by_name,
+        };
+
+        let mut config = ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(Arc::new(resolver));
+        config.alpn_protocols = self.alpn.clone();
+
+        Ok(Arc::new(config))
+    }
+}
+
+fn load_key(provider: &CryptoProvider, cert: &Path, key: &Path) -> io::Result<Arc<CertifiedKey>> {
+    let chain = CertificateDer::pem_slice_iter(&fs::read(cert)?)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| invalid(cert, e))?;
+    if chain.is_empty() {
+        return Err(invalid(cert, "no certificates found"));
+    }
+
+    let key_der = PrivateKeyDer::from_pem_slice(&fs::read(key)?).map_err(|e| invalid(key, e))?;
+
+    CertifiedKey::from_der(chain, key_der, provider)
+        .map(Arc::new)
+        .map_err(|e| invalid(key, e))
+}
+
+fn invalid(path: &Path, err: impl fmt::Display) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("{}: {err}", path.display()),
+    )
+}
+
+/// Picks a certificate by the SNI name in the ClientHello.
+#[derive(Debug)]
+struct SniResolver {
+    default: Arc<CertifiedKey>,
+    by_name: HashMap<String, Arc<CertifiedKey>>,
+}
+
+impl ResolvesServerCert for SniResolver {
+    fn resolve(&self, hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let key = hello
+            .server_name()
+            .and_then(|name| self.by_name.get(&name.to_ascii_lowercase()))
+            .unwrap_or(&self.default);
+
+        Some(key.clone())
+    }
+}
+
+/// Terminates TLS on top of another [`Listener`].
+///
+/// Handshakes run in their own tasks, so a slow client can't hold up the
+/// accept loop; [`Listener::accept`] only yields finished handshakes. While
+/// [`TlsListener::max_pending_handshakes`] are under way, new connections
+/// wait in the inner listener's backlog.
+pub struct TlsListener<L: Listener> {
+    inner: L,
+    reloader: TlsReloader,
+    handshake_timeout: Duration,
+    max_pending: usize,
+    handshakes: JoinSet<Handshake<L>>,
+}
+
+/// A finished handshake, or `None` if it failed.
+type Handshake<L> = Option<(TlsStream<<L as Listener>::Io>, <L as Listener>::Addr)>;
+
+impl<L: Listener> TlsListener<L> {
+    pub fn new(inner: L, config: TlsConfig) -> io::Result<Self> {
+        let acceptor = TlsAcceptor::from(config.load()?);
+
+        Ok(Self {
+            inner,
+            reloader: TlsReloader {
+                config: Arc::new(config),
+                acceptor: Arc::new(RwLock::new(acceptor)),
+            },
+            handshake_timeout: Duration::from_secs(10),
+            max_pending: 256,
+            handshakes: JoinSet::new(),
+        })
+    }
+
+    /// How long a client gets to complete the handshake. Defaults to 10s.
+    pub fn handshake_timeout(mut self, timeout: Duration) -> Self {
+        self.handshake_timeout = timeout;
+        self
+    }
+
+    /// How many handshakes may be under way at once. Defaults to 256.
+    pub fn max_pending_handshakes(mut self, max: usize) -> Self {
+        self.max_pending = max.max(1);
+        self
+    }
+
+    /// A handle that re-reads the certificates from disk.
+    pub fn reloader(&self) -> TlsReloader {
+        self.reloader.clone()
+    }
+
+    /// Reloads the certificates whenever the process receives SIGHUP.
+    /// Connections already established keep the certificate they started with.
+    #[cfg(unix)]
+    pub fn reload_on_sighup(self) -> io::Result<Self> {
+        use tokio::signal::unix::{SignalKind, signal};
+
+        let mut hangup = signal(SignalKind::hangup())?;
+        let reloader = self.reloader();
+
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                tracing::info!("SIGHUP received, reloading TLS certificates");
+                if let Err(err) = reloader.reload() {
+                    tracing::error!("failed to reload TLS certificates: {err}");
+                }
+            }
+        });
+
+        Ok(self)
+    }
+}
+
+impl<L: Listener> Listener for TlsListener<L> {
+    type Io = TlsStream<L::Io>;
+    type Addr = L::Addr;
+
+    async fn accept(&mut self) -> io::Result<(Self::Io, Self::Addr)> {
+        loop {
+            tokio::select! {
+                conn = self.inner.accept(), if self.handshakes.len() < self.max_pending => {
+                    let (io, addr) = conn?;
+                    let acceptor = self.reloader.acceptor();
+                    let timeout = self.handshake_timeout;
+
+                    self.handshakes.spawn(async move {
+                        match time::timeout(timeout, acceptor.accept(io)).await {
+                            Ok(Ok(tls)) => Some((tls, addr)),
+                            Ok(Err(err)) => {
+                                tracing::debug!("TLS handshake with {addr:?} failed: {err}");
+                                None
+                            }
+                            Err(_) => {
+                                tracing::debug!("TLS handshake with {addr:?} timed out");
+                                None
+                            }
+                        }
+                    });
+                }
+                Some(done) = self.handshakes.join_next(), if !self.handshakes.is_empty() => {
+                    if let Ok(Some(conn)) = done {
+                        return Ok(conn);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Swaps in freshly loaded certificates for a [`TlsListener`].
+#[derive(Clone)]
+pub struct TlsReloader {
+    config: Arc<TlsConfig>,
+    acceptor: Arc<RwLock<TlsAcceptor>>,
+}
+
+impl TlsReloader {
+    /// Re-reads the certificates. On error the previous ones stay in use.
+    pub fn reload(&self) -> io::Result<()> {
+        let acceptor = TlsAcceptor::from(self.config.load()?);
+        *self.acceptor.write().unwrap_or_else(|e| e.into_inner()) = acceptor;
+
+        Ok(())
+    }
+
+    fn acceptor(&self) -> TlsAcceptor {
+        self.acceptor
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Server;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+    use tokio_rustls::{
+        TlsConnector,
+        rustls::{ClientConfig, RootCertStore, pki_types::ServerName},
+    };
+
+    struct Cert {
+        der: CertificateDer<'static>,
+        cert: PathBuf,
+        key: PathBuf,
+    }
+
+    fn self_signed(dir: &Path, name: &str) -> Cert {
+        let generated = rcgen::generate_simple_self_signed(vec![name.to_string()]).unwrap();
+        let cert = dir.join(format!("{name}.crt"));
+        let key = dir.join(format!("{name}.key"));
+
+        fs::write(&cert, generated.cert.pem()).unwrap();
+        fs::write(&key, generated.signing_key.serialize_pem()).unwrap();
+
+        Cert {
+            der: generated.cert.der().clone(),
+            cert,
+            key,
+        }
+    }
+
+    fn temp_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("httpfromtcp-{}-{test}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    async fn connect(
+        addr: std::net::SocketAddr,
+        name: &'static str,
+        roots: &[&Cert],
+    ) -> tokio_rustls::client::TlsStream<TcpStream> {
+        let mut store = RootCertStore::empty();
+        for cert in roots {
+            store.add(cert.der.clone()).unwrap();
+        }
+
+        let mut config = ClientConfig::builder()
+            .with_root_certificates(store)
+            .with_no_client_auth();
+        config.alpn_protocols = vec![b"http/1.1".to_vec()];
+
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        TlsConnector::from(Arc::new(config))
+            .connect(ServerName::try_from(name).unwrap(), tcp)
+            .await
+            .unwrap()
+    }
+
+    fn peer_cert(tls: &tokio_rustls::client::TlsStream<TcpStream>) -> CertificateDer<'static> {
+        tls.get_ref().1.peer_certificates().unwrap()[0].clone()
+    }
+
+    #[tokio::test]
+    async fn serves_https_with_sni_and_alpn() {
+        let dir = temp_dir("sni");
+        let default = self_signed(&dir, "localhost");
+        let other = self_signed(&dir, "other.test");
+
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let config =
+            TlsConfig::new(&default.cert, &default.key).sni("other.test", &other.cert, &other.key);
+        let listener = TlsListener::new(tcp, config).unwrap();
+        tokio::spawn(
+            Server::builder()
+                .build()
+                .serve_with(listener, std::future::pending()),
+        );
+
+        let mut tls = connect(addr, "localhost", &[&default, &other]).await;
+        assert_eq!(default.der, peer_cert(&tls));
+        assert_eq!(
+            Some(b"http/1.1".as_slice()),
+            tls.get_ref().1.alpn_protocol()
+        );
+
+        tls.write_all(b"GET / HTTP/1.1\r\nConnection: close\r\n\r\n")
+            .await
+            .unwrap();
+        let mut res = String::new();
+        tls.read_to_string(&mut res).await.unwrap();
+        assert!(res.starts_with("HTTP/1.1 200 OK\r\n"));
+
+        let tls = connect(addr, "other.test", &[&default, &other]).await;
+        assert_eq!(other.der, peer_cert(&tls));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reload_picks_up_new_certificates() {
+        let dir = temp_dir("reload");
+        let first = self_signed(&dir, "localhost");
+
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let listener = TlsListener::new(tcp, TlsConfig::new(&first.cert, &first.key)).unwrap();
+        let reloader = listener.reloader();
+        tokio::spawn(
+            Server::builder()
+                .build()
+                .serve_with(listener, std::future::pending()),
+        );
+
+        let before = connect(addr, "localhost", &[&first]).await;
+        assert_eq!(first.der, peer_cert(&before));
+
+        let second = self_signed(&dir, "localhost");
+        reloader.reload().unwrap();
+
+        let after = connect(addr, "localhost", &[&second]).await;
+        assert_eq!(second.der, peer_cert(&after));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn caps_pending_handshakes() {
+        let dir = temp_dir("pending");
+        let cert = self_signed(&dir, "localhost");
+
+        let tcp = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let listener = TlsListener::new(tcp, TlsConfig::new(&cert.cert, &cert.key))
+            .unwrap()
+            .handshake_timeout(Duration::from_millis(300))
+            .max_pending_handshakes(1);
+        tokio::spawn(
+            Server::builder()
+                .build()
+                .serve_with(listener, std::future::pending()),
+        );
+
+        // A client that never says hello fills the only slot...
+        let _stalled = TcpStream::connect(addr).await.unwrap();
+        time::sleep(Duration::from_millis(50)).await;
+        let roots = [&cert];
+        let blocked = connect(addr, "localhost", &roots);
+        assert!(
+            time::timeout(Duration::from_millis(100), blocked)
+                .await
+                .is_err()
+        );
+
+        // ...until its handshake times out.
+        let tls = connect(addr, "localhost", &[&cert]).await;
+        assert_eq!(cert.der, peer_cert(&tls));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}